@@ -1,17 +1,58 @@
-use crate::connection::Connection;
-use serde::{Deserialize, Serialize};
+use crate::{
+    authentication::Authenticate, connection::Connection, prompt::Prompt, token::HardwareToken,
+};
 use std::error::Error;
+use std::fs;
 
 use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, EnumString};
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+use utils::{
+    crypto::{
+        generate_random_256_bits, generate_salt, srp_client_proof, srp_client_public, srp_verifier,
+    },
+    ChangePasswordData, EmailData, Error as UtilsError, KeyLabelData, RecoveryCodeData, Request,
+    Response, SecondFactor, SrpPublicData, TotpData, VerifyData, YubiKeyData,
+};
+use validation::Email;
 
 /// `Action` enum is used to perform logged operations:
 /// -   Enable/Disable 2fa authentication
-#[derive(Serialize, Deserialize, Debug, EnumString, EnumIter)]
+/// -   Enroll an authenticator app, replacing the previous one
+/// -   Regenerate the recovery codes, invalidating the previous ones
+/// -   List, enroll and revoke YubiKeys, keeping at least one
+/// -   Change the password, ending the other sessions
+/// -   Change the email, confirmed with a token mailed to the new one
+/// -   Export the stored data to a JSON file
+/// -   Delete the account
+///
+/// Disabling 2FA, enrolling a second factor, regenerating the recovery codes, revoking a key,
+/// changing the password or the email and deleting the account need a second factor again if none
+/// was proven recently in the session.
+#[derive(Debug, EnumString, EnumIter)]
 pub enum Action {
     #[strum(serialize = "Enable/Disable 2FA", serialize = "1")]
     Switch2FA,
-    #[strum(serialize = "Exit", serialize = "2")]
+    #[strum(serialize = "Enroll authenticator app", serialize = "2")]
+    EnrollTotp,
+    #[strum(serialize = "Regenerate recovery codes", serialize = "3")]
+    RegenerateRecoveryCodes,
+    #[strum(serialize = "List YubiKeys", serialize = "4")]
+    ListKeys,
+    #[strum(serialize = "Enroll YubiKey", serialize = "5")]
+    EnrollKey,
+    #[strum(serialize = "Revoke YubiKey", serialize = "6")]
+    RevokeKey,
+    #[strum(serialize = "Change password", serialize = "7")]
+    ChangePassword,
+    #[strum(serialize = "Change email", serialize = "8")]
+    ChangeEmail,
+    #[strum(serialize = "Export data", serialize = "9")]
+    ExportData,
+    #[strum(serialize = "Delete account", serialize = "10")]
+    DeleteAccount,
+    #[strum(serialize = "Exit", serialize = "11")]
     Logout,
 }
 
@@ -23,17 +64,62 @@ impl Action {
         }
     }
 
-    pub fn perform(&self, connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
-        connection.send(self)?;
+    /// Performs the action for `email`, the user logged in. Returns whether
+    /// the user is still logged in, which is not the case anymore once the
+    /// session expired.
+    pub fn perform(
+        &self,
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+        email: &Email,
+    ) -> Result<bool, Box<dyn Error>> {
+        match self.run(connection, token, prompt, email) {
+            Err(e) if matches!(e.downcast_ref(), Some(UtilsError::SessionExpired)) => {
+                println!("{}", e);
+                Ok(false)
+            }
+            result => result,
+        }
+    }
 
+    fn run(
+        &self,
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+        email: &Email,
+    ) -> Result<bool, Box<dyn Error>> {
         match self {
-            Action::Switch2FA => Action::switch_2fa(connection),
-            Action::Logout => Ok(false),
+            Action::Switch2FA => Action::switch_2fa(connection, token, prompt),
+            Action::EnrollTotp => Action::enroll_totp(connection, token, prompt, email),
+            Action::RegenerateRecoveryCodes => {
+                let request = Request::RegenerateRecoveryCodes;
+                let response = Action::sensitive(connection, token, prompt, &request)?;
+                Authenticate::show_recovery_codes(prompt, response)?;
+                Ok(true)
+            }
+            Action::ListKeys => Action::list_keys(connection),
+            Action::EnrollKey => Action::enroll_key(connection, token, prompt),
+            Action::RevokeKey => Action::revoke_key(connection, token, prompt),
+            Action::ChangePassword => Action::change_password(connection, token, prompt),
+            Action::ChangeEmail => Action::change_email(connection, token, prompt),
+            Action::ExportData => Action::export_data(connection, prompt),
+            Action::DeleteAccount => Action::delete_account(connection, token, prompt),
+            Action::Logout => Action::logout(connection),
         }
     }
 
-    fn switch_2fa(connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
-        let switch_2fa: utils::Switch2FA = connection.receive()?;
+    fn switch_2fa(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<bool, Box<dyn Error>> {
+        let Response::Switched2FA(switch_2fa) =
+            Action::sensitive(connection, token, prompt, &Request::Switch2FA)?
+        else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
         if switch_2fa.two_f_a {
             println!("\n2FA enabled!\n");
         } else {
@@ -41,4 +127,197 @@ impl Action {
         }
         Ok(true)
     }
+
+    fn enroll_totp(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+        email: &Email,
+    ) -> Result<bool, Box<dyn Error>> {
+        let request = Request::EnrollTotp(Authenticate::enroll_totp(prompt, email));
+        Action::print_success(Action::sensitive(connection, token, prompt, &request)?)
+    }
+
+    fn list_keys(connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
+        let Response::Keys(keys_data) = connection.request(&Request::ListKeys)? else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        println!();
+        for key in keys_data.keys {
+            let last_used = match key.last_used {
+                Some(last_used) => format_time(last_used)?,
+                None => "never".to_string(),
+            };
+            println!(
+                "{}\t{}, enrolled {}, last used {}",
+                *key.label,
+                key.algorithm,
+                format_time(key.created)?,
+                last_used
+            );
+        }
+        println!();
+        Ok(true)
+    }
+
+    /// Steps up with the YubiKey in use first, since the one to enroll cannot
+    /// prove anything yet, then generates a key on the YubiKey inserted, which
+    /// should not be one already enrolled since its key would be replaced.
+    fn enroll_key(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<bool, Box<dyn Error>> {
+        Action::step_up(connection, token, prompt)?;
+        println!("Insert the YubiKey to enroll");
+        let request = Request::EnrollKey(Authenticate::enroll_key(token, prompt)?);
+        Action::print_success(connection.request(&request)?)
+    }
+
+    fn revoke_key(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<bool, Box<dyn Error>> {
+        Action::list_keys(connection)?;
+        let request = Request::RevokeKey(KeyLabelData {
+            label: prompt.key_label(),
+        });
+        Action::print_success(Action::sensitive(connection, token, prompt, &request)?)
+    }
+
+    /// Proves the current password like a login does, then sets the new one.
+    fn change_password(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<bool, Box<dyn Error>> {
+        let a = generate_random_256_bits();
+        let password = prompt.password();
+        let request = Request::StartPasswordChange(SrpPublicData {
+            a_pub: srp_client_public(&a),
+        });
+        let Response::SrpChallenge(challenge_data) =
+            Action::sensitive(connection, token, prompt, &request)?
+        else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        let proof = srp_client_proof(&a, &password, &challenge_data.salt, &challenge_data.b_pub)?;
+
+        let new_password = prompt.new_password();
+        let salt = generate_salt();
+        let verifier = srp_verifier(&new_password, &salt)?;
+        let response = connection.request(&Request::ChangePassword(ChangePasswordData {
+            proof: proof.proof().to_vec(),
+            salt,
+            verifier,
+        }))?;
+        Action::print_success(response)
+    }
+
+    /// Moves the account to a new email, with the token mailed to it.
+    fn change_email(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<bool, Box<dyn Error>> {
+        let request = Request::StartEmailChange(EmailData {
+            email: prompt.email(),
+        });
+        Action::print_success(Action::sensitive(connection, token, prompt, &request)?)?;
+
+        let response = connection.request(&Request::ConfirmEmailChange(VerifyData {
+            token: prompt.token(),
+        }))?;
+        Action::print_success(response)
+    }
+
+    /// Writes the data the server stores about the user to a file.
+    fn export_data(
+        connection: &mut Connection,
+        prompt: &mut dyn Prompt,
+    ) -> Result<bool, Box<dyn Error>> {
+        let Response::Export(export_data) = connection.request(&Request::ExportData)? else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        let file = prompt.file();
+        fs::write(&file, export_data.document)?;
+        println!("\nData exported to {}\n", file.display());
+        Ok(true)
+    }
+
+    /// Deletes the account once confirmed, which logs the user out.
+    fn delete_account(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<bool, Box<dyn Error>> {
+        if !prompt.confirm("Delete the account? This cannot be undone") {
+            return Ok(true);
+        }
+        let response = Action::sensitive(connection, token, prompt, &Request::DeleteAccount)?;
+        Action::print_success(response)?;
+        Ok(false)
+    }
+
+    /// Sends `request`, proving a second factor first if the server asks for
+    /// it.
+    fn sensitive(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+        request: &Request,
+    ) -> Result<Response, Box<dyn Error>> {
+        match connection.request(request) {
+            Err(e) if matches!(e.downcast_ref(), Some(UtilsError::StepUpRequired)) => {
+                println!("{}", UtilsError::StepUpRequired);
+                Action::step_up(connection, token, prompt)?;
+                connection.request(request)
+            }
+            response => response,
+        }
+    }
+
+    fn step_up(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<(), Box<dyn Error>> {
+        let Response::StepUp(step_up_data) = connection.request(&Request::StartStepUp)? else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        let request =
+            match Authenticate::choose_second_factor(prompt, &step_up_data.second_factors)? {
+                SecondFactor::YubiKey => {
+                    let pin = prompt.pin();
+                    Request::ProveStepUp(YubiKeyData {
+                        yubikey: token.sign(&pin, &step_up_data.challenge)?,
+                    })
+                }
+                SecondFactor::Totp => Request::ProveStepUpTotp(TotpData { code: prompt.otp() }),
+                SecondFactor::RecoveryCode => Request::ProveStepUpRecoveryCode(RecoveryCodeData {
+                    code: prompt.recovery_code(),
+                }),
+            };
+        Action::print_success(connection.request(&request)?)?;
+        Ok(())
+    }
+
+    fn logout(connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
+        connection.request(&Request::Logout)?;
+        Ok(false)
+    }
+
+    fn print_success(response: Response) -> Result<bool, Box<dyn Error>> {
+        let Response::Success(message) = response else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        println!("\n{}\n", message);
+        Ok(true)
+    }
+}
+
+/// `seconds` since the Unix epoch, as a date.
+fn format_time(seconds: u64) -> Result<String, Box<dyn Error>> {
+    Ok(OffsetDateTime::from_unix_timestamp(seconds.try_into()?)?.format(&Rfc2822)?)
 }
@@ -1,22 +1,29 @@
-use crate::{connection::Connection, yubi::Yubi};
-use serde::{Deserialize, Serialize};
+use crate::{connection::Connection, prompt::Prompt, token::HardwareToken};
 use std::error::Error;
 
-use read_input::prelude::*;
 use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, EnumString};
 use utils::{
-    crypto::{hash_password, hash_sha256, hmac_sha256},
-    ChallengeData, ClientMessage, EmailData, HmacData, RegisterData, ServerMessage,
-    ServerMessage2FA, YubiKeyData,
+    crypto::{
+        generate_random_256_bits, generate_salt, generate_totp_secret, srp_client_proof,
+        srp_client_public, srp_verifier, totp_uri,
+    },
+    EmailData, Error as UtilsError, KeyData, PasswordData, RecoveryCodeData, RegisterData, Request,
+    Response, SecondFactor, SrpProofData, SrpStartData, Strings, TokenData, TotpData,
+    TotpEnrollData, VerifyData, YubiKeyData,
 };
-use validation::{Email, Password, Token};
+use validation::Email;
+
+/// Issuer shown by the authenticator apps.
+const TOTP_ISSUER: &str = "SEC";
 
 /// `Authenticate` enum is used to perform:
-/// -   User
+/// -   User, confirming the email on the first login
 /// -   Registration
-/// -   Password Reset
-#[derive(Serialize, Deserialize, Debug, EnumString, EnumIter)]
+/// -   Password Reset, possibly redeeming the mailed token in a later session
+/// -   Reverting an email change, with the token mailed to the previous email
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, EnumString, EnumIter)]
 pub enum Authenticate {
     #[strum(serialize = "Authenticate", serialize = "1")]
     Authenticate,
@@ -24,7 +31,11 @@ pub enum Authenticate {
     Register,
     #[strum(serialize = "Reset password", serialize = "3")]
     Reset,
-    #[strum(serialize = "Exit", serialize = "4")]
+    #[strum(serialize = "Redeem reset token", serialize = "4")]
+    RedeemToken,
+    #[strum(serialize = "Revert email change", serialize = "5")]
+    RevertEmail,
+    #[strum(serialize = "Exit", serialize = "6")]
     Exit,
 }
 
@@ -36,111 +47,289 @@ impl Authenticate {
         }
     }
 
-    pub fn perform(&self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        connection.send(self)?;
-
+    /// Returns the email of the user now logged in, if any: registering or
+    /// confirming the email requires to log in afterwards.
+    pub fn perform(
+        &self,
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<Option<Email>, Box<dyn Error>> {
         match self {
-            Authenticate::Authenticate => Authenticate::authenticate(connection),
-            Authenticate::Register => Authenticate::register(connection),
-            Authenticate::Reset => Authenticate::reset_password(connection),
+            Authenticate::Authenticate => Authenticate::authenticate(connection, token, prompt),
+            Authenticate::Register => {
+                Authenticate::register(connection, token, prompt)?;
+                Ok(None)
+            }
+            Authenticate::Reset => Authenticate::reset_password(connection, token, prompt),
+            Authenticate::RedeemToken => {
+                println!("\n\n<< Redeem reset token >>\n");
+                let email = prompt.email();
+                Authenticate::redeem_token(connection, prompt, email)
+            }
+            Authenticate::RevertEmail => Authenticate::revert_email(connection, prompt),
             Authenticate::Exit => {
+                connection.send(&Request::Exit)?;
                 println!("Exiting...");
                 std::process::exit(0);
             }
         }
     }
 
-    fn register(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        println!("\n\n<< Please register yourself >>\n");
+    /// Secret of a new authenticator app for `email`, shown to the user who
+    /// then enters a code to show it was enrolled.
+    pub(crate) fn enroll_totp(prompt: &mut dyn Prompt, email: &Email) -> TotpEnrollData {
+        let secret = generate_totp_secret();
+        prompt.show_totp(&totp_uri(&secret, TOTP_ISSUER, email));
+        TotpEnrollData {
+            secret,
+            code: prompt.otp(),
+        }
+    }
 
-        connection.send(&RegisterData {
-            email: input::<Email>().msg("- Email: ").get(),
-            password: input::<Password>().msg("- Password: ").get(),
-        })?;
+    /// Public key of a new key generated on `token`, under the label chosen.
+    pub(crate) fn enroll_key(
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<KeyData, Box<dyn Error>> {
+        Ok(KeyData {
+            label: prompt.key_label(),
+            public_key: token.generate()?,
+        })
+    }
 
-        Authenticate::receive_server_message(connection)?;
+    fn register(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<(), Box<dyn Error>> {
+        println!("\n\n<< Please register yourself >>\n");
 
-        connection.send(&YubiKeyData {
-            yubikey: Yubi::generate()?,
-        })?;
+        let email = prompt.email();
+        let password = prompt.password();
+        let salt = generate_salt();
+        let verifier = srp_verifier(&password, &salt)?;
+        let response = connection.request(&Request::Register(RegisterData {
+            email: email.clone(),
+            salt,
+            verifier,
+        }))?;
+        Authenticate::print_success(response)?;
 
-        Authenticate::receive_server_message(connection)?;
+        let request = match prompt.second_factor(&[SecondFactor::YubiKey, SecondFactor::Totp]) {
+            SecondFactor::Totp => Request::RegisterTotp(Authenticate::enroll_totp(prompt, &email)),
+            _ => Request::RegisterYubiKey(Authenticate::enroll_key(token, prompt)?),
+        };
+        Authenticate::show_recovery_codes(prompt, connection.request(&request)?)?;
+        println!("Server message: {}", Strings::UserRegistered);
+        Ok(())
+    }
 
+    /// Shows the recovery codes sent in `response`.
+    pub(crate) fn show_recovery_codes(
+        prompt: &mut dyn Prompt,
+        response: Response,
+    ) -> Result<(), Box<dyn Error>> {
+        let Response::RecoveryCodes(codes_data) = response else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        prompt.show_recovery_codes(&codes_data.codes);
         Ok(())
     }
 
-    fn authenticate(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+    fn authenticate(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<Option<Email>, Box<dyn Error>> {
         println!("\n\n<< Please authenticate yourself >>\n");
 
-        connection.send(&EmailData {
-            email: input::<Email>().msg("- Email: ").get(),
-        })?;
-        let password = input::<Password>().msg("- Password: ").get();
+        let a = generate_random_256_bits();
+        let email = prompt.email();
+        let password = prompt.password();
 
-        let challenge_data: ChallengeData = connection.receive()?;
-        let hash_password = hash_password(&password, &challenge_data.salt).unwrap();
-        match hmac_sha256(&challenge_data.challenge, &hash_password) {
-            Ok(hmac) => connection.send(&HmacData { hmac })?,
-            Err(e) => return Err(e.into()),
-        }
+        let Response::SrpChallenge(challenge_data) =
+            connection.request(&Request::StartAuthentication(SrpStartData {
+                email: email.clone(),
+                a_pub: srp_client_public(&a),
+            }))?
+        else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        let verifier =
+            srp_client_proof(&a, &password, &challenge_data.salt, &challenge_data.b_pub)?;
 
-        let server_message: ServerMessage2FA = connection.receive()?;
-        if !server_message.success {
-            return Err(server_message.message.into());
-        } else if !server_message.two_f_a {
-            println!("{}", server_message.message);
-            return Ok(());
+        let response = match connection.request(&Request::ProvePassword(SrpProofData {
+            proof: verifier.proof().to_vec(),
+        })) {
+            Err(e) if matches!(e.downcast_ref(), Some(UtilsError::EmailNotVerified)) => {
+                Authenticate::verify_email(connection, prompt)?;
+                return Ok(None);
+            }
+            response => response?,
+        };
+        let Response::PasswordVerified(verified_data) = response else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        verifier
+            .verify_server(&verified_data.proof)
+            .map_err(|e| e.to_string())?;
+        if !verified_data.two_f_a {
+            println!("{}", Strings::AuthSuccess);
+            return Ok(Some(email));
         }
-        println!("{}", server_message.message);
+        println!("{}", Strings::AuthTo2FA);
 
-        connection.send(&YubiKeyData {
-            yubikey: Yubi::sign(&hash_sha256(&challenge_data.challenge))?.to_vec(),
-        })?;
+        let response = Authenticate::prove_second_factor(
+            connection,
+            token,
+            prompt,
+            &verified_data.second_factors,
+            &challenge_data.challenge,
+        )?;
+        Authenticate::print_success(response)?;
+        Ok(Some(email))
+    }
 
-        Authenticate::receive_server_message(connection)?;
-        Ok(()) // TODO
+    /// Proves one of `factors`, signing `challenge` if it is the YubiKey.
+    fn prove_second_factor(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+        factors: &[SecondFactor],
+        challenge: &[u8],
+    ) -> Result<Response, Box<dyn Error>> {
+        match Authenticate::choose_second_factor(prompt, factors)? {
+            SecondFactor::YubiKey => {
+                let pin = prompt.pin();
+                connection.request(&Request::ProveYubiKey(YubiKeyData {
+                    yubikey: token.sign(&pin, challenge)?,
+                }))
+            }
+            SecondFactor::Totp => {
+                connection.request(&Request::ProveTotp(TotpData { code: prompt.otp() }))
+            }
+            SecondFactor::RecoveryCode => {
+                connection.request(&Request::ProveRecoveryCode(RecoveryCodeData {
+                    code: prompt.recovery_code(),
+                }))
+            }
+        }
     }
 
-    fn reset_password(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        println!("\n\n<< Reset password >>\n");
+    /// One of `factors`, asked only when there is a choice.
+    pub(crate) fn choose_second_factor(
+        prompt: &mut dyn Prompt,
+        factors: &[SecondFactor],
+    ) -> Result<SecondFactor, Box<dyn Error>> {
+        match factors {
+            [] => Err(UtilsError::UnexpectedResponse.into()),
+            [factor] => Ok(*factor),
+            _ => Ok(prompt.second_factor(factors)),
+        }
+    }
+
+    /// Confirms the email with the mailed token, optionally mailed again.
+    fn verify_email(
+        connection: &mut Connection,
+        prompt: &mut dyn Prompt,
+    ) -> Result<(), Box<dyn Error>> {
+        println!("{}", UtilsError::EmailNotVerified);
+        if prompt.confirm("Send a new verification email?") {
+            let response = connection.request(&Request::ResendVerification)?;
+            Authenticate::print_success(response)?;
+        }
 
-        connection.send(&EmailData {
-            email: input::<Email>().msg("- Email: ").get(),
-        })?;
+        let response = connection.request(&Request::VerifyEmail(VerifyData {
+            token: prompt.token(),
+        }))?;
+        Authenticate::print_success(response)
+    }
 
-        Authenticate::receive_server_message(connection)?;
+    fn reset_password(
+        connection: &mut Connection,
+        token: &mut dyn HardwareToken,
+        prompt: &mut dyn Prompt,
+    ) -> Result<Option<Email>, Box<dyn Error>> {
+        println!("\n\n<< Reset password >>\n");
 
-        let challenge_data: ChallengeData = connection.receive()?;
+        let email = prompt.email();
+        let Response::Challenge(challenge_data) =
+            connection.request(&Request::StartReset(EmailData {
+                email: email.clone(),
+            }))?
+        else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        println!("{}", Strings::AuthTo2FA);
 
-        connection.send(&YubiKeyData {
-            yubikey: Yubi::sign(&hash_sha256(&challenge_data.challenge))?.to_vec(),
-        })?;
+        let response = Authenticate::prove_second_factor(
+            connection,
+            token,
+            prompt,
+            &[
+                SecondFactor::YubiKey,
+                SecondFactor::Totp,
+                SecondFactor::RecoveryCode,
+            ],
+            &challenge_data.challenge,
+        )?;
+        Authenticate::print_success(response)?;
 
-        Authenticate::receive_server_message(connection)?;
+        Authenticate::redeem_token(connection, prompt, email)
+    }
 
-        connection.send(&ClientMessage {
-            message: input::<Token>().msg("- Token: ").get().to_string(),
-        })?;
+    /// Sets a new password with the token mailed to `email`, which is then
+    /// logged in.
+    fn redeem_token(
+        connection: &mut Connection,
+        prompt: &mut dyn Prompt,
+        email: Email,
+    ) -> Result<Option<Email>, Box<dyn Error>> {
+        let response = connection.request(&Request::RedeemToken(TokenData {
+            email: email.clone(),
+            token: prompt.token(),
+        }))?;
+        Authenticate::print_success(response)?;
+        Authenticate::set_password(connection, prompt, email)
+    }
 
-        Authenticate::receive_server_message(connection)?;
+    /// Moves the account back to `email`, the previous email to which the
+    /// token was mailed. The user then logs in or resets the password.
+    fn revert_email(
+        connection: &mut Connection,
+        prompt: &mut dyn Prompt,
+    ) -> Result<Option<Email>, Box<dyn Error>> {
+        println!("\n\n<< Revert email change >>\n");
 
-        connection.send(&ClientMessage {
-            message: input::<Password>()
-                .msg("- New password: ")
-                .get()
-                .to_string(),
-        })?;
+        let email = prompt.email();
+        let response = connection.request(&Request::RevertEmail(TokenData {
+            email,
+            token: prompt.token(),
+        }))?;
+        Authenticate::print_success(response)?;
+        Ok(None)
+    }
 
-        Ok(()) // TODO
+    fn set_password(
+        connection: &mut Connection,
+        prompt: &mut dyn Prompt,
+        email: Email,
+    ) -> Result<Option<Email>, Box<dyn Error>> {
+        let password = prompt.new_password();
+        let salt = generate_salt();
+        let verifier = srp_verifier(&password, &salt)?;
+        let response =
+            connection.request(&Request::SetPassword(PasswordData { salt, verifier }))?;
+        Authenticate::print_success(response)?;
+        Ok(Some(email))
     }
 
-    fn receive_server_message(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        let server_message: ServerMessage = connection.receive()?;
-        if server_message.success {
-            println!("Server message: {}", server_message.message);
-        } else {
-            return Err(server_message.message.into());
-        }
+    fn print_success(response: Response) -> Result<(), Box<dyn Error>> {
+        let Response::Success(message) = response else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        println!("Server message: {}", message);
         Ok(())
     }
 }
@@ -0,0 +1,70 @@
+use serde::Deserialize;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Client configuration, read from a RON file.
+///
+/// ```ron
+/// (
+///     address: "127.0.0.1:8080",
+///     transport: Tls(
+///         server_cert: "cert.pem",
+///         server_name: "localhost",
+///     ),
+///     device: YubiKey,
+/// )
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub address: String,
+    pub transport: Transport,
+    pub device: Device,
+}
+
+/// Encrypted channel used to talk with the server.
+#[derive(Deserialize, Clone, Debug)]
+pub enum Transport {
+    /// Only the certificates found in `server_cert` are trusted, which pins the
+    /// server certificate when it is self-signed.
+    Tls {
+        server_cert: PathBuf,
+        server_name: String,
+    },
+    /// Hex encoded Noise static public key of the server.
+    Noise { server_public_key: String },
+}
+
+/// Device holding the second factor key.
+#[derive(Deserialize, Clone, Debug)]
+pub enum Device {
+    /// First YubiKey found on the PC/SC readers.
+    YubiKey,
+    /// Software key unlocked with `pin`, kept in `path` or only in memory if
+    /// `None`. Meant for tests, the key is not protected beyond being readable
+    /// by the owner only, and a key file is never replaced by a new key.
+    Software { path: Option<PathBuf>, pin: String },
+}
+
+impl Config {
+    /// Loads the configuration, falling back to the defaults if the file is missing.
+    pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        Ok(ron::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "127.0.0.1:8080".to_string(),
+            transport: Transport::Tls {
+                server_cert: PathBuf::from("cert.pem"),
+                server_name: "localhost".to_string(),
+            },
+            device: Device::YubiKey,
+        }
+    }
+}
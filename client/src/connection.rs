@@ -1,30 +1,112 @@
-use std::net::{TcpStream};
-use serde::Serialize;
+use crate::config::{Config, Transport};
+use rustls::{Certificate, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use utils::frame::{read_frame, write_frame, PROTOCOL_VERSION};
+use utils::noise::NoiseStream;
+use utils::{Error as UtilsError, HelloData, Request, Response};
+
+trait Stream: Read + Write {}
+
+impl<S: Read + Write> Stream for S {}
 
 pub struct Connection {
-    stream: TcpStream
+    stream: Box<dyn Stream>,
 }
 
 impl Connection {
-    pub fn new(addr: &str) -> Connection {
-        let stream = TcpStream::connect(addr);
-        let stream = match stream {
+    pub fn new(config: &Config) -> Connection {
+        let connection = match Connection::connect(config) {
             Err(e) => panic!("Connection ended up with error: {}", e),
-            Ok(s) => s
+            Ok(c) => c,
         };
 
         println!("Connection to server is UP.\n");
 
-        Connection{stream}
+        connection
+    }
+
+    pub fn connect(config: &Config) -> Result<Connection, Box<dyn Error>> {
+        let stream = TcpStream::connect(&config.address)?;
+        let stream: Box<dyn Stream> = match &config.transport {
+            Transport::Tls {
+                server_cert,
+                server_name,
+            } => Box::new(Connection::connect_tls(stream, server_cert, server_name)?),
+            Transport::Noise { server_public_key } => Box::new(NoiseStream::connect(
+                stream,
+                &hex::decode(server_public_key)?,
+            )?),
+        };
+
+        let mut connection = Connection { stream };
+        connection.hello()?;
+        Ok(connection)
+    }
+
+    fn hello(&mut self) -> Result<(), Box<dyn Error>> {
+        self.send(&HelloData {
+            version: PROTOCOL_VERSION,
+        })?;
+        let hello: HelloData = self.receive()?;
+        if hello.version != PROTOCOL_VERSION {
+            return Err(UtilsError::IncompatibleVersion(hello.version).into());
+        }
+        Ok(())
     }
 
-    pub fn send<T>(&mut self, o: &T) -> Result<(), Box<dyn Error>> where T: Serialize {
-        Ok(bincode::serialize_into(&self.stream, &o)?)
+    fn connect_tls(
+        stream: TcpStream,
+        server_cert: &Path,
+        server_name: &str,
+    ) -> Result<StreamOwned<ClientConnection, TcpStream>, Box<dyn Error>> {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(server_cert)?))? {
+            roots.add(&Certificate(cert))?;
+        }
+
+        let tls = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let session = ClientConnection::new(Arc::new(tls), server_name.try_into()?)?;
+        let mut stream = StreamOwned::new(session, stream);
+
+        // Complete the handshake now so certificate errors surface here.
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+
+        Ok(stream)
     }
 
-    pub fn receive<T>(&mut self) -> Result<T, Box<dyn Error>> where T: DeserializeOwned {
-        Ok(bincode::deserialize_from(&self.stream)?)
+    /// Sends a request and returns the server response, turning
+    /// `Response::Error` into an error.
+    pub fn request(&mut self, request: &Request) -> Result<Response, Box<dyn Error>> {
+        self.send(request)?;
+        match self.receive()? {
+            Response::Error(e) => Err(e.into()),
+            response => Ok(response),
+        }
     }
-}
\ No newline at end of file
+
+    pub fn send<T>(&mut self, o: &T) -> Result<(), Box<dyn Error>>
+    where
+        T: Serialize,
+    {
+        write_frame(&mut self.stream, o)
+    }
+
+    pub fn receive<T>(&mut self) -> Result<T, Box<dyn Error>>
+    where
+        T: DeserializeOwned,
+    {
+        read_frame(&mut self.stream)
+    }
+}
@@ -0,0 +1,8 @@
+pub mod action;
+pub mod authentication;
+pub mod config;
+pub mod connection;
+pub mod prompt;
+pub mod software;
+pub mod token;
+pub mod yubi;
@@ -1,31 +1,39 @@
-mod action;
-mod authentication;
-mod connection;
-mod yubi;
-
-use crate::action::Action;
-use crate::authentication::Authenticate;
-use crate::connection::Connection;
+use client::action::Action;
+use client::authentication::Authenticate;
+use client::config::Config;
+use client::connection::Connection;
+use client::prompt::Console;
+use client::token;
 use read_input::prelude::*;
+use std::path::PathBuf;
 
-const SERVER_IP: &str = "127.0.0.1:8080";
+const CONFIG_PATH: &str = "client.ron";
 
 fn main() {
     // Setup
     println!("--- Client ---");
-    let mut connection = Connection::new(SERVER_IP);
+    let config_path = std::env::args()
+        .nth(1)
+        .map_or_else(|| PathBuf::from(CONFIG_PATH), PathBuf::from);
+    let config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => panic!("Invalid configuration: {}", e),
+    };
+    let mut connection = Connection::new(&config);
+    let mut token = token::open(&config.device);
 
     loop {
         // Authentication
-        loop {
+        let email = loop {
             Authenticate::display();
             let action = input::<Authenticate>().msg("Please select: ").get();
 
-            match action.perform(&mut connection) {
-                Ok(_) => break,
+            match action.perform(&mut connection, token.as_mut(), &mut Console) {
+                Ok(Some(email)) => break email,
+                Ok(None) => {}
                 Err(e) => eprintln!("Authentication failed with following errors: {}\n", e),
             };
-        }
+        };
 
         println!("\n[[ Authentication success ]]\n");
 
@@ -33,7 +41,7 @@ fn main() {
             Action::display();
             let action = input::<Action>().msg("Please select: ").get();
 
-            match action.perform(&mut connection) {
+            match action.perform(&mut connection, token.as_mut(), &mut Console, &email) {
                 Ok(end) => {
                     if !end {
                         break;
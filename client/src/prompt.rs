@@ -0,0 +1,118 @@
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use read_input::prelude::*;
+use std::path::PathBuf;
+use utils::SecondFactor;
+use validation::{Email, KeyLabel, Otp, Password, Pin, RecoveryCode, Token};
+
+/// Source of the values the flows ask the user for.
+pub trait Prompt {
+    fn email(&mut self) -> Email;
+    fn password(&mut self) -> Password;
+    fn new_password(&mut self) -> Password;
+    fn pin(&mut self) -> Pin;
+    /// Label of a YubiKey, to enroll or revoke.
+    fn key_label(&mut self) -> KeyLabel;
+    /// Token received by email.
+    fn token(&mut self) -> Token;
+    /// Second factor to use among those enrolled.
+    fn second_factor(&mut self, factors: &[SecondFactor]) -> SecondFactor;
+    /// Shows the `otpauth://` URI of a new authenticator app secret.
+    fn show_totp(&mut self, uri: &str);
+    /// Code of the authenticator app.
+    fn otp(&mut self) -> Otp;
+    /// Shows new recovery codes, which the server never sends again.
+    fn show_recovery_codes(&mut self, codes: &[String]);
+    fn recovery_code(&mut self) -> RecoveryCode;
+    /// Answer to a yes or no `question`.
+    fn confirm(&mut self, question: &str) -> bool;
+    /// Path of a file to write to.
+    fn file(&mut self) -> PathBuf;
+}
+
+/// Asks on the terminal until a valid value is entered.
+pub struct Console;
+
+impl Prompt for Console {
+    fn email(&mut self) -> Email {
+        input::<Email>().msg("- Email: ").get()
+    }
+
+    fn password(&mut self) -> Password {
+        input::<Password>().msg("- Password: ").get()
+    }
+
+    fn new_password(&mut self) -> Password {
+        input::<Password>().msg("- New password: ").get()
+    }
+
+    fn pin(&mut self) -> Pin {
+        input::<Pin>().msg("- PIN: ").get()
+    }
+
+    fn key_label(&mut self) -> KeyLabel {
+        input::<KeyLabel>().msg("- Key label: ").get()
+    }
+
+    fn token(&mut self) -> Token {
+        input::<Token>().msg("- Token: ").get()
+    }
+
+    fn second_factor(&mut self, factors: &[SecondFactor]) -> SecondFactor {
+        for (i, factor) in factors.iter().enumerate() {
+            println!("{}.\t{}", i + 1, factor);
+        }
+        let choice = input::<usize>()
+            .inside(1..=factors.len())
+            .msg("- Second factor: ")
+            .get();
+        factors[choice - 1]
+    }
+
+    /// Prints the URI as a QR code to scan, along with the URI itself for the
+    /// apps that cannot.
+    fn show_totp(&mut self, uri: &str) {
+        match QrCode::new(uri) {
+            Ok(code) => println!(
+                "{}",
+                code.render::<Dense1x2>()
+                    .dark_color(Dense1x2::Light)
+                    .light_color(Dense1x2::Dark)
+                    .build()
+            ),
+            Err(e) => eprintln!("Cannot render the QR code: {}", e),
+        }
+        println!(
+            "Scan the QR code with your authenticator app, or enter:\n{}\n",
+            uri
+        );
+    }
+
+    fn otp(&mut self) -> Otp {
+        input::<Otp>().msg("- Authenticator code: ").get()
+    }
+
+    fn show_recovery_codes(&mut self, codes: &[String]) {
+        println!("\nRecovery codes, each usable once instead of your second factor.");
+        println!("Keep them somewhere safe, they will not be shown again:\n");
+        for code in codes {
+            println!("\t{}", code);
+        }
+        println!();
+    }
+
+    fn recovery_code(&mut self) -> RecoveryCode {
+        input::<RecoveryCode>().msg("- Recovery code: ").get()
+    }
+
+    fn confirm(&mut self, question: &str) -> bool {
+        input::<String>()
+            .msg(format!("- {} [y/N]: ", question))
+            .get()
+            .eq_ignore_ascii_case("y")
+    }
+
+    fn file(&mut self) -> PathBuf {
+        input::<String>().msg("- File: ").get().into()
+    }
+}
@@ -0,0 +1,78 @@
+use crate::token::HardwareToken;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey, VerifyingKey};
+use rand_core::OsRng;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use validation::Pin;
+
+/// P-256 key held in software, for testing without a YubiKey.
+///
+/// The key is kept hex encoded in `path`, or only in memory without one, and
+/// like on a YubiKey it can only be used with the right PIN. A key file is
+/// readable by the owner only and never replaced.
+pub struct SoftwareToken {
+    path: Option<PathBuf>,
+    pin: String,
+    key: Option<SigningKey>,
+}
+
+impl SoftwareToken {
+    pub fn new(path: Option<PathBuf>, pin: &str) -> SoftwareToken {
+        SoftwareToken {
+            path,
+            pin: pin.to_string(),
+            key: None,
+        }
+    }
+
+    fn key(&mut self) -> Result<&SigningKey, Box<dyn Error>> {
+        if self.key.is_none() {
+            let Some(path) = &self.path else {
+                return Err("No key generated on the software token".into());
+            };
+            let key = hex::decode(fs::read_to_string(path)?.trim())?;
+            self.key = Some(SigningKey::from_bytes(&key)?);
+        }
+        Ok(self.key.as_ref().unwrap())
+    }
+
+    /// Saves `key` in a new file at `path`, failing if one already exists.
+    fn save(path: &Path, key: &SigningKey) -> Result<(), Box<dyn Error>> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(path)
+            .map_err(|e| format!("Cannot create the key {}: {}", path.display(), e))?;
+        file.write_all(hex::encode(key.to_bytes()).as_bytes())?;
+        Ok(())
+    }
+}
+
+impl HardwareToken for SoftwareToken {
+    fn generate(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let key = SigningKey::random(OsRng);
+        if let Some(path) = &self.path {
+            SoftwareToken::save(path, &key)?;
+        }
+
+        let public_key = VerifyingKey::from(&key).to_encoded_point(false);
+        self.key = Some(key);
+        Ok(public_key.as_bytes().to_vec())
+    }
+
+    fn sign(&mut self, pin: &Pin, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if pin.as_str() != self.pin {
+            return Err("Wrong PIN".into());
+        }
+        let signature: Signature = self.key()?.sign(message);
+        Ok(signature.to_der().as_bytes().to_vec())
+    }
+}
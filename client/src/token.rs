@@ -0,0 +1,23 @@
+use crate::config::Device;
+use crate::software::SoftwareToken;
+use crate::yubi::Yubi;
+use std::error::Error;
+use validation::Pin;
+
+/// Device holding the P-256 key used as second factor.
+pub trait HardwareToken {
+    /// Generates a new key and returns its SEC1 encoded public key.
+    fn generate(&mut self) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    /// Unlocks the key with `pin` and returns the DER encoded ECDSA/SHA-256
+    /// signature of `message`.
+    fn sign(&mut self, pin: &Pin, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>;
+}
+
+/// Opens the device selected in the configuration.
+pub fn open(device: &Device) -> Box<dyn HardwareToken> {
+    match device {
+        Device::YubiKey => Box::new(Yubi),
+        Device::Software { path, pin } => Box::new(SoftwareToken::new(path.clone(), pin)),
+    }
+}
@@ -1,11 +1,14 @@
-use read_input::prelude::*;
+use crate::token::HardwareToken;
+use std::error::Error;
 use std::io;
 use std::io::Read;
+use utils::crypto::hash_sha256;
 use x509::SubjectPublicKeyInfo;
 use yubikey::*;
 
 use validation::Pin;
 
+/// YubiKey PIV applet, using the key in the authentication slot.
 pub struct Yubi;
 
 impl Yubi {
@@ -21,8 +24,10 @@ impl Yubi {
             let _ = io::stdin().read(&mut [0u8]).unwrap();
         }
     }
+}
 
-    pub fn generate() -> Result<Vec<u8>> {
+impl HardwareToken for Yubi {
+    fn generate(&mut self) -> std::result::Result<Vec<u8>, Box<dyn Error>> {
         let mut yubikey = Yubi::auto_yk()?;
         yubikey.authenticate(MgmKey::default())?;
         Ok(piv::generate(
@@ -35,14 +40,15 @@ impl Yubi {
         .public_key())
     }
 
-    pub fn sign(bytes: &[u8]) -> Result<Buffer> {
+    fn sign(&mut self, pin: &Pin, message: &[u8]) -> std::result::Result<Vec<u8>, Box<dyn Error>> {
         let mut yubikey = Yubi::auto_yk()?;
-        yubikey.verify_pin(input::<Pin>().msg("- PIN: ").get().as_bytes())?;
+        yubikey.verify_pin(pin.as_bytes())?;
         Ok(piv::sign_data(
             &mut yubikey,
-            bytes,
+            &hash_sha256(message),
             piv::AlgorithmId::EccP256,
             piv::SlotId::Authentication,
-        )?)
+        )?
+        .to_vec())
     }
 }
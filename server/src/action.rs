@@ -1,35 +1,407 @@
-use crate::{connection::Connection, database::Database};
-use serde::{Deserialize, Serialize};
-use std::error::Error;
+use crate::{
+    authentication::Authenticate,
+    context::Context,
+    database::Purpose,
+    export::Export,
+    mailer::Notification,
+    session::{State, Transition},
+};
+use std::net::IpAddr;
+use std::time::Instant;
 
+use utils::crypto::{
+    generate_random_128_bits, generate_random_256_bits, srp_server_proof, srp_server_public,
+};
 use utils::*;
 
-/// `Action` enum is used to perform logged operations:
+/// `Action` handles the requests used to perform logged operations:
 /// -   Enable/Disable 2fa authentication
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Action {
-    Switch2FA,
-    Logout,
-}
+/// -   Enroll an authenticator app
+/// -   Regenerate the recovery codes
+/// -   List, enroll and revoke YubiKeys
+/// -   Change the password
+/// -   Change the email
+/// -   Export the stored data
+/// -   Delete the account
+/// -   Logout
+pub struct Action;
 
 impl Action {
-    pub fn perform(user: &mut User, connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
-        match connection.receive()? {
-            Action::Switch2FA => Action::switch_2fa(user, connection),
-            Action::Logout => Ok(false),
+    pub fn switch_2fa(context: &Context, peer: IpAddr, user: User) -> Transition {
+        log::info!("Changing 2FA account status");
+        let user = context
+            .store
+            .modify(&user.email, &mut |user| {
+                user.two_f_a = !user.two_f_a;
+                true
+            })?
+            .ok_or(Error::InvalidEmail)?;
+
+        let two_f_a = user.two_f_a;
+        context
+            .mailer
+            .notify(&user.email, peer, &Notification::TwoFAChanged { two_f_a });
+        Ok((
+            State::Authenticated { user },
+            Response::Switched2FA(Switch2FA { two_f_a }),
+        ))
+    }
+
+    /// Replaces the authenticator app, if any.
+    pub fn enroll_totp(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        totp: TotpEnrollData,
+    ) -> Transition {
+        log::info!("Enrolling an authenticator app");
+        let Some(credential) = context.totp.enroll(totp.secret, &totp.code) else {
+            log::error!("{}", Error::TotpFailed);
+            return Ok((
+                State::Authenticated { user },
+                Response::Error(Error::TotpFailed),
+            ));
+        };
+        let user = context
+            .store
+            .modify(&user.email, &mut |user| {
+                user.totp = Some(credential.clone());
+                true
+            })?
+            .ok_or(Error::InvalidEmail)?;
+
+        context.mailer.notify(
+            &user.email,
+            peer,
+            &Notification::FactorAdded {
+                factor: SecondFactor::Totp,
+                label: None,
+            },
+        );
+        Ok((
+            State::Authenticated { user },
+            Response::Success(Strings::TotpEnrolled),
+        ))
+    }
+
+    /// Replaces the recovery codes, the previous ones cannot be used anymore.
+    pub fn regenerate_recovery_codes(context: &Context, peer: IpAddr, user: User) -> Transition {
+        log::info!("Regenerating the recovery codes");
+        let (codes, hashes) = context.recovery.generate();
+        let user = context
+            .store
+            .modify(&user.email, &mut |user| {
+                user.recovery_codes = hashes.clone();
+                true
+            })?
+            .ok_or(Error::InvalidEmail)?;
+
+        context.mailer.notify(
+            &user.email,
+            peer,
+            &Notification::FactorAdded {
+                factor: SecondFactor::RecoveryCode,
+                label: None,
+            },
+        );
+        Ok((
+            State::Authenticated { user },
+            Response::RecoveryCodes(RecoveryCodesData { codes }),
+        ))
+    }
+
+    pub fn list_keys(user: User) -> Transition {
+        let keys = user.yubikeys.clone();
+        Ok((
+            State::Authenticated { user },
+            Response::Keys(KeysData { keys }),
+        ))
+    }
+
+    /// Adds a YubiKey, kept along the ones already enrolled.
+    pub fn enroll_key(context: &Context, peer: IpAddr, user: User, key: KeyData) -> Transition {
+        log::info!("Enrolling a YubiKey");
+        let mut invalid = false;
+        let enrolled = match Authenticate::new_key(&user, key)? {
+            Some(key) => context.store.modify(&user.email, &mut |user| {
+                // Checked again in case a key got the label meanwhile.
+                invalid = user.yubikeys.iter().any(|other| other.label == key.label);
+                if !invalid {
+                    user.yubikeys.push(key.clone());
+                }
+                !invalid
+            })?,
+            None => {
+                invalid = true;
+                None
+            }
+        };
+        if invalid {
+            log::error!("{}", Error::InvalidKey);
+            return Ok((
+                State::Authenticated { user },
+                Response::Error(Error::InvalidKey),
+            ));
         }
+        let user = enrolled.ok_or(Error::InvalidEmail)?;
+        let label = user.yubikeys.last().unwrap().label.to_string();
+
+        context.mailer.notify(
+            &user.email,
+            peer,
+            &Notification::FactorAdded {
+                factor: SecondFactor::YubiKey,
+                label: Some(label),
+            },
+        );
+        Ok((
+            State::Authenticated { user },
+            Response::Success(Strings::KeyEnrolled),
+        ))
     }
 
-    fn switch_2fa(user: &mut User, connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
-        log::info!("Changing 2FA account status");
-        user.two_f_a = !user.two_f_a;
+    /// Removes a YubiKey, unless it is the last one.
+    pub fn revoke_key(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        key: KeyLabelData,
+    ) -> Transition {
+        log::info!("Revoking a YubiKey");
+        let mut error = None;
+        let revoked = context.store.modify(&user.email, &mut |user| {
+            error = match user
+                .yubikeys
+                .iter()
+                .position(|other| other.label == key.label)
+            {
+                None => Some(Error::UnknownKey),
+                Some(_) if user.yubikeys.len() == 1 => Some(Error::LastKey),
+                Some(_) => None,
+            };
+            user.yubikeys
+                .retain(|other| other.label != key.label || error.is_some());
+            error.is_none()
+        })?;
+        if let Some(error) = error {
+            log::error!("{}", error);
+            return Ok((State::Authenticated { user }, Response::Error(error)));
+        }
+        let user = revoked.ok_or(Error::InvalidEmail)?;
+
+        context.mailer.notify(
+            &user.email,
+            peer,
+            &Notification::FactorRemoved {
+                factor: SecondFactor::YubiKey,
+                label: Some(key.label.to_string()),
+            },
+        );
+        Ok((
+            State::Authenticated { user },
+            Response::Success(Strings::KeyRevoked),
+        ))
+    }
+
+    /// Starts the SRP exchange proving the current password.
+    /// Proving the current password counts like a login, so it is throttled
+    /// the same way.
+    pub fn start_password_change(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        start_data: SrpPublicData,
+    ) -> Transition {
+        log::info!("---Password change process---");
+        if let Some(throttled) = Authenticate::throttled(context, peer, &user.email)? {
+            return Ok((State::Authenticated { user }, throttled));
+        }
+
+        let b = generate_random_256_bits();
+        let response = Response::SrpChallenge(SrpChallengeData {
+            salt: user.salt.clone(),
+            b_pub: srp_server_public(&b, &user.verifier),
+            challenge: generate_random_128_bits(),
+        });
+        Ok((
+            State::PasswordChangePending {
+                user,
+                b,
+                a_pub: start_data.a_pub,
+            },
+            response,
+        ))
+    }
+
+    /// Replaces the password if the current one is proven, which ends the
+    /// other sessions of the user. A wrong proof counts as a failed login.
+    pub fn change_password(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        b: &[u8],
+        a_pub: &[u8],
+        password_data: ChangePasswordData,
+    ) -> Transition {
+        if let Some(throttled) = Authenticate::throttled(context, peer, &user.email)? {
+            return Ok((State::Authenticated { user }, throttled));
+        }
+
+        log::info!("Checking user SRP proof");
+        if srp_server_proof(b, &user.verifier, a_pub, &password_data.proof).is_err() {
+            log::error!("{}", Error::AuthFailed);
+            context.throttle.failed(&user.email, peer)?;
+            return Ok((
+                State::Authenticated { user },
+                Response::Error(Error::AuthFailed),
+            ));
+        }
+        context.throttle.succeeded(&user.email)?;
+
+        log::info!("Updating user");
+        let user = context
+            .store
+            .modify(&user.email, &mut |user| {
+                user.salt = password_data.salt.clone();
+                user.verifier = password_data.verifier.clone();
+                true
+            })?
+            .ok_or(Error::InvalidEmail)?;
 
-        Database::insert(&user)?;
+        context
+            .mailer
+            .notify(&user.email, peer, &Notification::PasswordChanged);
+        Ok((
+            State::Authenticated { user },
+            Response::Success(Strings::PasswordChanged),
+        ))
+    }
+
+    /// Mails a token to the new email, unless it already has an account.
+    /// Either way the response is the same and takes at least
+    /// `min_response_time`.
+    pub fn start_email_change(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        email_data: EmailData,
+    ) -> Transition {
+        log::info!("---Email change process---");
+        let started = Instant::now();
+        if context.store.get(&email_data.email)?.is_none() {
+            let (token, expires) = context.tokens.issue_moving(
+                &user.email,
+                Purpose::ChangeEmail,
+                Some(&email_data.email),
+            )?;
+            log::info!("Sending token to the new email");
+            context.mailer.send(
+                &email_data.email,
+                peer,
+                &Notification::EmailChange { token, expires },
+            )?;
+        } else {
+            log::warn!("{}", Error::UserAlreadyExist);
+        }
+
+        if let Some(rest) = context
+            .config
+            .min_response_time()
+            .checked_sub(started.elapsed())
+        {
+            std::thread::sleep(rest);
+        }
+        Ok((
+            State::Authenticated { user },
+            Response::Success(Strings::EmailSent),
+        ))
+    }
+
+    /// Moves the account to the new email once the mailed token proves it,
+    /// which ends the other sessions. The previous email is told, with a
+    /// token to revert the change.
+    pub fn confirm_email_change(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        verify_data: VerifyData,
+    ) -> Transition {
+        log::info!("Comparing email change tokens");
+        let Some(email) = context.tokens.redeem_moving(
+            &user.email,
+            Purpose::ChangeEmail,
+            verify_data.token.as_str(),
+        )?
+        else {
+            log::error!("{}", Error::UuidFailed);
+            return Ok((
+                State::Authenticated { user },
+                Response::Error(Error::UuidFailed),
+            ));
+        };
+
+        let previous = user.email.clone();
+        log::info!("Moving user in the database");
+        let moved = match context.store.rekey(&previous, &email) {
+            Ok(moved) => moved,
+            Err(e) if matches!(e.downcast_ref(), Some(Error::UserAlreadyExist)) => {
+                log::error!("{}", Error::UserAlreadyExist);
+                return Ok((
+                    State::Authenticated { user },
+                    Response::Error(Error::UserAlreadyExist),
+                ));
+            }
+            Err(e) => return Err(e),
+        };
+
+        let (token, expires) =
+            context
+                .tokens
+                .issue_moving(&previous, Purpose::RevertEmail, Some(&moved.email))?;
+        context.mailer.notify(
+            &previous,
+            peer,
+            &Notification::EmailChanged {
+                email: moved.email.to_string(),
+                token,
+                expires,
+            },
+        );
+        Ok((
+            State::Authenticated { user: moved },
+            Response::Success(Strings::EmailChanged),
+        ))
+    }
+
+    pub fn export_data(context: &Context, user: User) -> Transition {
+        log::info!("Exporting user data");
+        let document = Export::collect(context.store.as_ref(), &user)?.to_json()?;
+        Ok((
+            State::Authenticated { user },
+            Response::Export(ExportData { document }),
+        ))
+    }
+
+    /// Removes the account with its tokens, which ends its sessions.
+    pub fn delete_account(context: &Context, peer: IpAddr, user: User) -> Transition {
+        log::info!("Deleting user");
+        context.store.delete(&user.email)?;
+
+        context
+            .mailer
+            .notify(&user.email, peer, &Notification::AccountDeleted);
+        log::info!("{}", Strings::AccountDeleted);
+        Ok((
+            State::Unauthenticated,
+            Response::Success(Strings::AccountDeleted),
+        ))
+    }
 
-        connection
-            .send(&Switch2FA {
-                two_f_a: user.two_f_a,
-            })
-            .map(|_| true)
+    pub fn logout() -> Transition {
+        log::info!("{}", Strings::LoggedOut);
+        Ok((
+            State::Unauthenticated,
+            Response::Success(Strings::LoggedOut),
+        ))
     }
 }
@@ -1,282 +1,792 @@
-use crate::{connection::Connection, database::Database, mailer::send_mail};
+use crate::{
+    context::Context,
+    database::Purpose,
+    mailer::Notification,
+    session::{State, StepUp, Transition},
+};
 use ecdsa::signature::Verifier;
+use p256::ecdsa::SigningKey;
 use p256::EncodedPoint;
-use serde::{Deserialize, Serialize};
+use rand::rngs::OsRng;
 use std::error::Error;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use utils::{
-    crypto::{generate_random_128_bits, generate_salt, hash_password, hmac_sha256},
-    ChallengeData, ClientMessage, EmailData, Error as UtilsError, HmacData, RegisterData,
-    ServerMessage, ServerMessage2FA, Strings, User, YubiKeyData,
+    crypto::{
+        derive_salt, generate_random_128_bits, generate_random_256_bits, srp_server_proof,
+        srp_server_public,
+    },
+    ChallengeData, EmailData, Error as UtilsError, KeyAlgorithm, KeyCredential, KeyData,
+    PasswordData, PasswordVerifiedData, RecoveryCodeData, RecoveryCodesData, RegisterData,
+    Response, SrpChallengeData, SrpProofData, SrpStartData, StepUpData, Strings, TokenData,
+    TotpData, TotpEnrollData, User, VerifyData, YubiKeyData,
 };
-use uuid::Uuid;
+use validation::Email;
 
-/// `Authenticate` enum is used to perform:
+/// `Authenticate` handles the requests used to perform:
 /// -   Authentication
 /// -   Registration
 /// -   Password Reset
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub enum Authenticate {
-    Authenticate,
-    Register,
-    Reset,
-    Exit,
-}
+pub struct Authenticate;
 
 impl Authenticate {
-    pub fn perform(connection: &mut Connection) -> Result<Option<User>, Box<dyn Error>> {
-        match connection.receive()? {
-            Authenticate::Authenticate => Authenticate::authenticate(connection),
-            Authenticate::Register => Authenticate::register(connection),
-            Authenticate::Reset => Authenticate::reset_password(connection),
-            Authenticate::Exit => Err("Client disconnected")?,
-        }
+    pub fn register(register_data: RegisterData) -> Transition {
+        log::info!("--- Registation process ---");
+
+        log::info!("{}", Strings::YubiKeyPubInfo);
+        let user = User {
+            email: register_data.email,
+            salt: register_data.salt,
+            verifier: register_data.verifier,
+            two_f_a: true,
+            yubikeys: vec![],
+            totp: None,
+            recovery_codes: vec![],
+            verified: false,
+        };
+        Ok((
+            State::Registering { user },
+            Response::Success(Strings::YubiKeyPubInfo),
+        ))
     }
 
-    fn register(connection: &mut Connection) -> Result<Option<User>, Box<dyn Error>> {
-        let register_data: RegisterData = connection.receive()?;
-        log::info!("--- Registation process ---");
+    pub fn register_yubikey(
+        context: &Context,
+        peer: IpAddr,
+        mut user: User,
+        key: KeyData,
+    ) -> Transition {
+        log::info!("Getting YubiKey public info");
+        let Some(key) = Authenticate::new_key(&user, key)? else {
+            log::error!("{}", UtilsError::InvalidKey);
+            return Ok((
+                State::Registering { user },
+                Response::Error(UtilsError::InvalidKey),
+            ));
+        };
+        user.yubikeys.push(key);
+        Authenticate::create_account(context, peer, user)
+    }
 
-        if Database::get(&register_data.email)?.is_some() {
-            log::error!("{}", UtilsError::UserAlreadyExist);
-            connection.send(&ServerMessage {
-                message: UtilsError::UserAlreadyExist.to_string(),
-                success: false,
-            })?;
-            return Err(Box::new(UtilsError::UserAlreadyExist));
+    /// Uses an authenticator app as second factor instead of a YubiKey.
+    pub fn register_totp(
+        context: &Context,
+        peer: IpAddr,
+        mut user: User,
+        totp: TotpEnrollData,
+    ) -> Transition {
+        log::info!("Enrolling the authenticator app");
+        let Some(credential) = context.totp.enroll(totp.secret, &totp.code) else {
+            log::error!("{}", UtilsError::TotpFailed);
+            return Ok((
+                State::Registering { user },
+                Response::Error(UtilsError::TotpFailed),
+            ));
+        };
+        user.totp = Some(credential);
+        Authenticate::create_account(context, peer, user)
+    }
+
+    /// Creates the account pending its email verification, replacing an
+    /// account still pending but leaving a verified one untouched, whose owner
+    /// is told by email instead. Either way the response is the same, with
+    /// recovery codes that only work if the account was created, and takes at
+    /// least `min_response_time`.
+    fn create_account(context: &Context, peer: IpAddr, mut user: User) -> Transition {
+        let started = Instant::now();
+        let (codes, hashes) = context.recovery.generate();
+        user.recovery_codes = hashes;
+
+        let mut found = false;
+        let replaced = context.store.modify(&user.email, &mut |existing| {
+            found = true;
+            if existing.verified {
+                return false;
+            }
+            *existing = user.clone();
+            true
+        })?;
+        let pending = match replaced {
+            Some(_) => {
+                log::info!("Replaced the unverified user in the database");
+                true
+            }
+            None if found => false,
+            None => {
+                log::info!("Inserting user in the database");
+                match context.store.insert(&user) {
+                    Ok(()) => true,
+                    Err(e) if matches!(e.downcast_ref(), Some(UtilsError::UserAlreadyExist)) => {
+                        false
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        if pending {
+            let notification = Authenticate::verification(context, &user.email)?;
+            context.mailer.notify(&user.email, peer, &notification);
         } else {
-            log::info!("{}", Strings::YubiKeyPubInfo);
-            connection.send(&ServerMessage {
-                message: Strings::YubiKeyPubInfo.to_string(),
-                success: true,
-            })?;
+            log::warn!("{}", UtilsError::UserAlreadyExist);
+            context
+                .mailer
+                .notify(&user.email, peer, &Notification::AlreadyRegistered);
         }
 
-        log::info!("Generating the salt");
-        let salt = generate_salt();
-        log::info!("Hashing the password");
-        let hash_password = hash_password(&register_data.password, &salt).unwrap();
-
-        log::info!("Getting YubiKey public info");
-        let yubikey: YubiKeyData = connection.receive()?;
+        if let Some(rest) = context
+            .config
+            .min_response_time()
+            .checked_sub(started.elapsed())
+        {
+            std::thread::sleep(rest);
+        }
+        log::info!("{}", Strings::UserRegistered);
+        Ok((
+            State::Unauthenticated,
+            Response::RecoveryCodes(RecoveryCodesData { codes }),
+        ))
+    }
 
-        log::info!("Creating the user");
-        let user = User {
-            email: register_data.email,
-            salt,
-            hash_password,
-            two_f_a: true,
-            yubikey: yubikey.yubikey,
-        };
+    /// Confirms the email with the mailed token, the user then has to log in.
+    pub fn verify_email(context: &Context, user: User, verify_data: VerifyData) -> Transition {
+        log::info!("Comparing verification tokens");
+        if !context
+            .tokens
+            .redeem(&user.email, Purpose::Verify, verify_data.token.as_str())?
+        {
+            log::error!("{}", UtilsError::UuidFailed);
+            return Ok((
+                State::EmailUnverified { user },
+                Response::Error(UtilsError::UuidFailed),
+            ));
+        }
 
-        connection.send(&ServerMessage {
-            message: Strings::UserRegistered.to_string(),
-            success: true,
+        context.store.modify(&user.email, &mut |user| {
+            user.verified = true;
+            true
         })?;
-        log::info!("Inserting user in the database");
-        Database::insert(&user).map(|_| Some(user))
+        log::info!("{}", Strings::EmailVerified);
+        Ok((
+            State::Unauthenticated,
+            Response::Success(Strings::EmailVerified),
+        ))
     }
 
-    fn authenticate(connection: &mut Connection) -> Result<Option<User>, Box<dyn Error>> {
+    /// Mails a new verification token, replacing the previous one.
+    pub fn resend_verification(context: &Context, peer: IpAddr, user: User) -> Transition {
+        let notification = Authenticate::verification(context, &user.email)?;
+        log::info!("Sending verification token to the user email");
+        context.mailer.send(&user.email, peer, &notification)?;
+        Ok((
+            State::EmailUnverified { user },
+            Response::Success(Strings::EmailSent),
+        ))
+    }
+
+    pub fn start_authentication(
+        context: &Context,
+        peer: IpAddr,
+        start_data: SrpStartData,
+    ) -> Transition {
         log::info!("---Authentication process---");
-        log::info!("Getting user email");
-        let email_data: EmailData = connection.receive()?;
 
-        let mut user = User::default();
-        let mut salt: String = user.salt.clone();
-        let valid: bool;
+        if let Some(throttled) = Authenticate::throttled(context, peer, &start_data.email)? {
+            return Ok((State::Unauthenticated, throttled));
+        }
+
+        let mut user = Authenticate::dummy_user(&start_data.email);
+        let mut valid = false;
 
         log::info!("Looking for the input email inside the DB");
-        match Database::get(&email_data.email)? {
-            Some(db_user) => {
-                salt = db_user.salt.clone();
-                valid = true;
-                user = db_user;
-            }
-            None => valid = false,
+        if let Some(db_user) = context.store.get(&start_data.email)? {
+            valid = true;
+            user = db_user;
         }
 
-        log::info!("Generating and sending challenge");
+        log::info!("Generating and sending SRP and 2FA challenges");
+        let b = generate_random_256_bits();
         let challenge = generate_random_128_bits();
-        connection.send(&ChallengeData { salt, challenge })?;
+        let response = Response::SrpChallenge(SrpChallengeData {
+            salt: user.salt.clone(),
+            b_pub: srp_server_public(&b, &user.verifier),
+            challenge,
+        });
+        Ok((
+            State::PasswordPending {
+                user,
+                valid,
+                b,
+                a_pub: start_data.a_pub,
+                challenge,
+            },
+            response,
+        ))
+    }
 
-        log::info!("Generating the HMAC");
-        let hmac = match hmac_sha256(&challenge, &user.hash_password) {
-            Ok(hmac) => hmac,
-            Err(e) => return Err(e.into()),
+    #[allow(clippy::too_many_arguments)]
+    pub fn prove_password(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        valid: bool,
+        b: &[u8],
+        a_pub: &[u8],
+        challenge: [u8; 16],
+        proof_data: SrpProofData,
+    ) -> Transition {
+        log::info!("Checking user SRP proof");
+        let proof = match srp_server_proof(b, &user.verifier, a_pub, &proof_data.proof) {
+            Ok(proof) if valid => proof,
+            _ => {
+                log::error!("{}", UtilsError::AuthFailed);
+                context.throttle.failed(&user.email, peer)?;
+                return Ok((
+                    State::Unauthenticated,
+                    Response::Error(UtilsError::AuthFailed),
+                ));
+            }
         };
-        log::info!("Getting user HMAC");
-        let hmac_data: HmacData = connection.receive()?;
-        if hmac_data.hmac != hmac || !valid {
-            log::error!("{}", UtilsError::AuthFailed);
-            connection.send(&ServerMessage2FA {
-                message: UtilsError::AuthFailed.to_string(),
-                success: false,
-                two_f_a: false,
-            })?;
-            return Err(UtilsError::AuthFailed.into());
-        } else if user.two_f_a {
+
+        if !user.verified {
+            log::error!("{}", UtilsError::EmailNotVerified);
+            return Ok((
+                State::EmailUnverified { user },
+                Response::Error(UtilsError::EmailNotVerified),
+            ));
+        }
+
+        let two_f_a = user.two_f_a;
+        let second_factors = user.second_factors();
+        let state = if two_f_a {
             log::info!("{}", Strings::AuthTo2FA);
-            connection.send(&ServerMessage2FA {
-                message: Strings::AuthTo2FA.to_string(),
-                success: true,
-                two_f_a: true,
-            })?;
+            State::PasswordVerified { user, challenge }
         } else {
             log::info!("{}", Strings::AuthSuccess);
-            connection.send(&ServerMessage2FA {
-                message: Strings::AuthSuccess.to_string(),
-                success: true,
-                two_f_a: false,
-            })?;
-            return Ok(Some(user));
+            context.throttle.succeeded(&user.email)?;
+            context
+                .mailer
+                .notify(&user.email, peer, &Notification::NewLogin);
+            State::Authenticated { user }
+        };
+        Ok((
+            state,
+            Response::PasswordVerified(PasswordVerifiedData {
+                proof,
+                two_f_a,
+                second_factors,
+            }),
+        ))
+    }
+
+    pub fn prove_yubikey(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        challenge: &[u8],
+        yubikey: YubiKeyData,
+    ) -> Transition {
+        log::info!("Getting user yubikey signature");
+        match Authenticate::prove_key(context, &user.email, &yubikey.yubikey, challenge)? {
+            Some(user) => Authenticate::logged_in(context, peer, user),
+            None => Authenticate::failed(context, peer, &user.email, UtilsError::TwoFAFailed),
         }
-        log::info!("Getting user yubikey public info");
-        let client_message: YubiKeyData = connection.receive()?;
-
-        match Authenticate::verify_yubikey_challenge(
-            &user.yubikey,
-            &client_message.yubikey,
-            &challenge,
-        ) {
-            Ok(_) => {
-                log::info!("{}", Strings::AuthSuccess);
-                connection.send(&ServerMessage {
-                    message: Strings::AuthSuccess.to_string(),
-                    success: true,
-                })?;
-                Ok(Some(user))
-            }
-            Err(e) => {
-                log::error!("{}", UtilsError::TwoFAFailed);
-                connection.send(&ServerMessage {
-                    message: UtilsError::TwoFAFailed.to_string(),
-                    success: false,
-                })?;
-                Err(e.into())
+    }
+
+    pub fn prove_totp(context: &Context, peer: IpAddr, user: User, totp: TotpData) -> Transition {
+        log::info!("Checking user TOTP code");
+        match context.totp.verify(&user.email, &totp.code)? {
+            Some(user) => Authenticate::logged_in(context, peer, user),
+            None => Authenticate::failed(context, peer, &user.email, UtilsError::TotpFailed),
+        }
+    }
+
+    pub fn prove_recovery_code(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        recovery: RecoveryCodeData,
+    ) -> Transition {
+        log::info!("Checking user recovery code");
+        match context.recovery.redeem(&user.email, &recovery.code)? {
+            Some(user) => Authenticate::logged_in(context, peer, user),
+            None => {
+                Authenticate::failed(context, peer, &user.email, UtilsError::RecoveryCodeFailed)
             }
         }
     }
 
-    fn reset_password(connection: &mut Connection) -> Result<Option<User>, Box<dyn Error>> {
+    /// Challenges the logged in user to prove a second factor again.
+    pub fn start_step_up(step_up: &mut StepUp, user: User) -> Transition {
+        log::info!("Generating the step-up challenge");
+        let challenge = generate_random_128_bits();
+        *step_up = StepUp::Pending { challenge };
+        let second_factors = user.second_factors();
+        Ok((
+            State::Authenticated { user },
+            Response::StepUp(StepUpData {
+                challenge,
+                second_factors,
+            }),
+        ))
+    }
+
+    /// Allows the sensitive actions for a while if the challenge is signed.
+    /// Each step-up can only be tried once, and failures count as failed
+    /// logins.
+    pub fn prove_step_up(
+        context: &Context,
+        peer: IpAddr,
+        step_up: &mut StepUp,
+        user: User,
+        yubikey: YubiKeyData,
+    ) -> Transition {
+        let Some(challenge) = Authenticate::step_up_challenge(step_up) else {
+            return Authenticate::step_up_unexpected(user);
+        };
+        if let Some(throttled) = Authenticate::throttled(context, peer, &user.email)? {
+            return Ok((State::Authenticated { user }, throttled));
+        }
+
+        log::info!("Getting user yubikey signature");
+        let proven = Authenticate::prove_key(context, &user.email, &yubikey.yubikey, &challenge)?;
+        Authenticate::stepped_up(
+            context,
+            peer,
+            step_up,
+            user,
+            proven,
+            UtilsError::TwoFAFailed,
+        )
+    }
+
+    /// Step-up with a TOTP code, which cannot be replayed.
+    pub fn prove_step_up_totp(
+        context: &Context,
+        peer: IpAddr,
+        step_up: &mut StepUp,
+        user: User,
+        totp: TotpData,
+    ) -> Transition {
+        if Authenticate::step_up_challenge(step_up).is_none() {
+            return Authenticate::step_up_unexpected(user);
+        }
+        if let Some(throttled) = Authenticate::throttled(context, peer, &user.email)? {
+            return Ok((State::Authenticated { user }, throttled));
+        }
+
+        log::info!("Checking user TOTP code");
+        let proven = context.totp.verify(&user.email, &totp.code)?;
+        Authenticate::stepped_up(context, peer, step_up, user, proven, UtilsError::TotpFailed)
+    }
+
+    /// Step-up with a recovery code, which is used up.
+    pub fn prove_step_up_recovery_code(
+        context: &Context,
+        peer: IpAddr,
+        step_up: &mut StepUp,
+        user: User,
+        recovery: RecoveryCodeData,
+    ) -> Transition {
+        if Authenticate::step_up_challenge(step_up).is_none() {
+            return Authenticate::step_up_unexpected(user);
+        }
+        if let Some(throttled) = Authenticate::throttled(context, peer, &user.email)? {
+            return Ok((State::Authenticated { user }, throttled));
+        }
+
+        log::info!("Checking user recovery code");
+        let proven = context.recovery.redeem(&user.email, &recovery.code)?;
+        Authenticate::stepped_up(
+            context,
+            peer,
+            step_up,
+            user,
+            proven,
+            UtilsError::RecoveryCodeFailed,
+        )
+    }
+
+    /// Answers an unknown email with a challenge as well, which then fails
+    /// like a wrong YubiKey.
+    pub fn start_reset(context: &Context, peer: IpAddr, email_data: EmailData) -> Transition {
         log::info!("---Reset password process---");
-        log::info!("Getting user email");
 
-        let email_data: EmailData = connection.receive()?;
-        let user = Database::get(&email_data.email)?;
-        let mut challenge: [u8; 16] = [0; 16];
+        if let Some(throttled) = Authenticate::throttled(context, peer, &email_data.email)? {
+            return Ok((State::Unauthenticated, throttled));
+        }
 
         log::info!("Retreiving user");
-        if user.is_none() {
-            log::error!("{}", UtilsError::InvalidEmail);
-            connection.send(&ServerMessage {
-                message: UtilsError::InvalidEmail.to_string(),
-                success: false,
-            })?;
-            return Err(Box::new(UtilsError::InvalidEmail));
-        } else {
-            log::info!("{}", Strings::AuthTo2FA);
-            challenge = generate_random_128_bits();
-            connection.send(&ServerMessage {
-                message: Strings::AuthTo2FA.to_string(),
-                success: true,
-            })?;
-
-            log::info!("Sending challenge");
-            connection.send(&ChallengeData {
-                salt: String::new(),
-                challenge,
-            })?;
+        let user = match context.store.get(&email_data.email)? {
+            Some(user) => user,
+            None => Authenticate::dummy_user(&email_data.email),
+        };
+
+        log::info!("{}", Strings::AuthTo2FA);
+        let challenge = generate_random_128_bits();
+        Ok((
+            State::ResetPending { user, challenge },
+            Response::Challenge(ChallengeData { challenge }),
+        ))
+    }
+
+    pub fn prove_reset_yubikey(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        challenge: &[u8],
+        yubikey: YubiKeyData,
+    ) -> Transition {
+        match Authenticate::prove_key(context, &user.email, &yubikey.yubikey, challenge)? {
+            Some(user) => Authenticate::send_token(context, peer, &user.email),
+            None => Authenticate::failed(context, peer, &user.email, UtilsError::TwoFAFailed),
         }
-        let user = user.unwrap();
-        let client_message: YubiKeyData = connection.receive()?;
-
-        match Authenticate::verify_yubikey_challenge(
-            &user.yubikey,
-            &client_message.yubikey,
-            &challenge,
-        ) {
-            Ok(_) => {
-                connection.send(&ServerMessage {
-                    message: Strings::EmailSent.to_string(),
-                    success: true,
-                })?;
-            }
-            Err(e) => {
-                connection.send(&ServerMessage {
-                    message: UtilsError::TwoFAFailed.to_string(),
-                    success: false,
-                })?;
-                return Err(e.into());
-            }
+    }
+
+    /// Fails for an unknown email, which has no recovery codes.
+    pub fn prove_reset_recovery_code(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        recovery: RecoveryCodeData,
+    ) -> Transition {
+        log::info!("Checking user recovery code");
+        if context
+            .recovery
+            .redeem(&user.email, &recovery.code)?
+            .is_none()
+        {
+            return Authenticate::failed(
+                context,
+                peer,
+                &user.email,
+                UtilsError::RecoveryCodeFailed,
+            );
         }
+        Authenticate::send_token(context, peer, &user.email)
+    }
 
-        let token = Authenticate::send_token(
-            &user.email,
-            Strings::EmailSubject.to_string().as_str(),
-            Strings::EmailMessage.to_string().as_str(),
-        )?;
-        let token_user: ClientMessage = connection.receive()?;
+    /// Fails for an unknown email, which has no authenticator app.
+    pub fn prove_reset_totp(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        totp: TotpData,
+    ) -> Transition {
+        log::info!("Checking user TOTP code");
+        if context.totp.verify(&user.email, &totp.code)?.is_none() {
+            return Authenticate::failed(context, peer, &user.email, UtilsError::TotpFailed);
+        }
+        Authenticate::send_token(context, peer, &user.email)
+    }
 
+    pub fn redeem_token(context: &Context, token_data: TokenData) -> Transition {
         log::info!("Comparing tokens");
-        if token != token_user.message {
+        let redeemed =
+            context
+                .tokens
+                .redeem(&token_data.email, Purpose::Reset, token_data.token.as_str())?;
+        let user = match context.store.get(&token_data.email)? {
+            Some(user) if redeemed => user,
+            _ => {
+                log::error!("{}", UtilsError::UuidFailed);
+                return Ok((
+                    State::Unauthenticated,
+                    Response::Error(UtilsError::UuidFailed),
+                ));
+            }
+        };
+
+        log::info!("{}", Strings::UuidSuccess);
+        Ok((
+            State::TokenVerified { user },
+            Response::Success(Strings::UuidSuccess),
+        ))
+    }
+
+    /// Moves the account back to the email the revert token was mailed to. The
+    /// token proves the email only, so the user then logs in, or resets the
+    /// password with a second factor since whoever changed the email knew it.
+    pub fn revert_email(context: &Context, token_data: TokenData) -> Transition {
+        log::info!("Comparing revert tokens");
+        let target = context.tokens.redeem_moving(
+            &token_data.email,
+            Purpose::RevertEmail,
+            token_data.token.as_str(),
+        )?;
+        let user = match target {
+            Some(email) => context.store.get(&email)?,
+            None => None,
+        };
+        let Some(user) = user else {
             log::error!("{}", UtilsError::UuidFailed);
-            connection.send(&ServerMessage {
-                message: UtilsError::UuidFailed.to_string(),
-                success: false,
-            })?;
-            return Err(UtilsError::UuidFailed.into());
-        } else {
-            log::info!("{}", Strings::UuidSuccess);
-            connection.send(&ServerMessage {
-                message: Strings::UuidSuccess.to_string(),
-                success: true,
-            })?;
-        }
+            return Ok((
+                State::Unauthenticated,
+                Response::Error(UtilsError::UuidFailed),
+            ));
+        };
 
-        log::info!("Getting new password");
-        let new_password: ClientMessage = connection.receive()?;
+        log::info!("Moving user back in the database");
+        match context.store.rekey(&user.email, &token_data.email) {
+            Ok(_) => {}
+            Err(e) if matches!(e.downcast_ref(), Some(UtilsError::InvalidEmail)) => {
+                log::error!("{}", UtilsError::UuidFailed);
+                return Ok((
+                    State::Unauthenticated,
+                    Response::Error(UtilsError::UuidFailed),
+                ));
+            }
+            Err(e) if matches!(e.downcast_ref(), Some(UtilsError::UserAlreadyExist)) => {
+                log::error!("{}", UtilsError::UserAlreadyExist);
+                return Ok((
+                    State::Unauthenticated,
+                    Response::Error(UtilsError::UserAlreadyExist),
+                ));
+            }
+            Err(e) => return Err(e),
+        }
 
-        log::info!("Generating the salt");
-        let salt = generate_salt();
-        log::info!("Hashing the password");
-        let hash_password = hash_password(&new_password.message, &salt).unwrap();
+        log::info!("{}", Strings::EmailReverted);
+        Ok((
+            State::Unauthenticated,
+            Response::Success(Strings::EmailReverted),
+        ))
+    }
 
+    /// Redeeming the mailed token proved the email as well.
+    pub fn set_password(
+        context: &Context,
+        peer: IpAddr,
+        user: User,
+        password_data: PasswordData,
+    ) -> Transition {
         log::info!("Updating user");
-        let user = User {
-            email: user.email,
-            salt,
-            hash_password,
-            two_f_a: user.two_f_a,
-            yubikey: user.yubikey,
-        };
-        Database::insert(&user).map(|_| Some(user))
+        let user = context
+            .store
+            .modify(&user.email, &mut |user| {
+                user.salt = password_data.salt.clone();
+                user.verifier = password_data.verifier.clone();
+                user.verified = true;
+                true
+            })?
+            .ok_or(UtilsError::InvalidEmail)?;
+
+        context
+            .mailer
+            .notify(&user.email, peer, &Notification::PasswordChanged);
+        Ok((
+            State::Authenticated { user },
+            Response::Success(Strings::PasswordReset),
+        ))
     }
 
-    fn verify_yubikey_challenge(
-        yubikey: &Vec<u8>,
-        message: &Vec<u8>,
+    /// New YubiKey of `user`, none if its public key is not a P-256 point or
+    /// its label is already used by another key of `user`.
+    pub fn new_key(user: &User, key: KeyData) -> Result<Option<KeyCredential>, Box<dyn Error>> {
+        let valid_point = EncodedPoint::from_bytes(&key.public_key)
+            .ok()
+            .and_then(|point| p256::ecdsa::VerifyingKey::from_encoded_point(&point).ok())
+            .is_some();
+        if !valid_point || user.yubikeys.iter().any(|other| other.label == key.label) {
+            return Ok(None);
+        }
+        Ok(Some(KeyCredential {
+            label: key.label,
+            public_key: key.public_key,
+            algorithm: KeyAlgorithm::EcdsaP256,
+            created: now()?,
+            last_used: None,
+        }))
+    }
+
+    /// Key of a dummy user, whose private key is thrown away, so that its
+    /// signatures are checked as thoroughly as a real user's.
+    /// Stand-in for an unknown `email`. Its salt is derived from the email
+    /// with a secret of the server, so that it is the same at each attempt
+    /// like the salt of a real account, until the server restarts.
+    fn dummy_user(email: &Email) -> User {
+        static DUMMY_SECRET: OnceLock<[u8; 32]> = OnceLock::new();
+        let secret = DUMMY_SECRET.get_or_init(generate_random_256_bits);
+        User {
+            email: email.clone(),
+            salt: derive_salt(secret, email.as_str().as_bytes()),
+            yubikeys: vec![Authenticate::dummy_yubikey().clone()],
+            ..User::default()
+        }
+    }
+
+    fn dummy_yubikey() -> &'static KeyCredential {
+        static DUMMY_YUBIKEY: OnceLock<KeyCredential> = OnceLock::new();
+        DUMMY_YUBIKEY.get_or_init(|| KeyCredential {
+            label: "YubiKey".parse().unwrap(),
+            public_key: SigningKey::random(&mut OsRng)
+                .verifying_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec(),
+            algorithm: KeyAlgorithm::EcdsaP256,
+            created: 0,
+            last_used: None,
+        })
+    }
+
+    /// Current record of `email` with the key that signed `challenge` marked
+    /// as used, none if none of its keys made the signature `message`. The
+    /// keys are those stored now, not when the challenge was sent, so that a
+    /// key revoked meanwhile is refused. An unknown email is checked against
+    /// the dummy key.
+    fn prove_key(
+        context: &Context,
+        email: &Email,
+        message: &[u8],
         challenge: &[u8],
-    ) -> Result<(), Box<dyn Error>> {
-        let encoded_point: EncodedPoint = match EncodedPoint::from_bytes(yubikey) {
-            Ok(encoded_point) => encoded_point,
-            Err(e) => return Err(e.into()),
-        };
-        let verifying_key = p256::ecdsa::VerifyingKey::from_encoded_point(&encoded_point)?;
-        let signature = p256::ecdsa::Signature::from_der(message)?;
+    ) -> Result<Option<User>, Box<dyn Error>> {
+        let used = now()?;
+        let mut found = false;
+        let user = context.store.modify(email, &mut |user| {
+            found = true;
+            match Authenticate::verify_yubikey_challenge(&user.yubikeys, message, challenge) {
+                Ok(index) => {
+                    user.yubikeys[index].last_used = Some(used);
+                    true
+                }
+                Err(e) => {
+                    log::error!("{}: {}", UtilsError::TwoFAFailed, e);
+                    false
+                }
+            }
+        })?;
+        if !found {
+            let dummy = std::slice::from_ref(Authenticate::dummy_yubikey());
+            if let Err(e) = Authenticate::verify_yubikey_challenge(dummy, message, challenge) {
+                log::error!("{}: {}", UtilsError::TwoFAFailed, e);
+            }
+        }
+        Ok(user)
+    }
+
+    /// Takes the challenge of the pending step-up, so that it is tried once.
+    fn step_up_challenge(step_up: &mut StepUp) -> Option<[u8; 16]> {
+        match std::mem::take(step_up) {
+            StepUp::Pending { challenge } => Some(challenge),
+            _ => None,
+        }
+    }
+
+    fn step_up_unexpected(user: User) -> Transition {
+        log::error!("{}", UtilsError::UnexpectedRequest);
+        Ok((
+            State::Authenticated { user },
+            Response::Error(UtilsError::UnexpectedRequest),
+        ))
+    }
+
+    /// Marks the step-up as proven if `proven` holds the updated user, or
+    /// counts a failure against `user` otherwise.
+    fn stepped_up(
+        context: &Context,
+        peer: IpAddr,
+        step_up: &mut StepUp,
+        user: User,
+        proven: Option<User>,
+        error: UtilsError,
+    ) -> Transition {
+        match proven {
+            Some(user) => {
+                context.throttle.succeeded(&user.email)?;
+                *step_up = StepUp::Proven { at: Instant::now() };
+                log::info!("{}", Strings::StepUpSuccess);
+                Ok((
+                    State::Authenticated { user },
+                    Response::Success(Strings::StepUpSuccess),
+                ))
+            }
+            None => {
+                log::error!("{}", error);
+                context.throttle.failed(&user.email, peer)?;
+                Ok((State::Authenticated { user }, Response::Error(error)))
+            }
+        }
+    }
 
+    /// Refusal to send while `email` may not be tried from `peer`.
+    pub fn throttled(
+        context: &Context,
+        peer: IpAddr,
+        email: &Email,
+    ) -> Result<Option<Response>, Box<dyn Error>> {
+        let wait = context.throttle.wait(email, peer)?;
+        if wait == 0 {
+            return Ok(None);
+        }
+        log::error!("{}", UtilsError::Throttled(wait));
+        Ok(Some(Response::Error(UtilsError::Throttled(wait))))
+    }
+
+    /// Index of the key in `yubikeys` whose signature of `challenge` is
+    /// `message`.
+    fn verify_yubikey_challenge(
+        yubikeys: &[KeyCredential],
+        message: &[u8],
+        challenge: &[u8],
+    ) -> Result<usize, Box<dyn Error>> {
         log::info!("Verifying yubikey");
-        match verifying_key.verify(&challenge, &signature) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+        for (index, yubikey) in yubikeys.iter().enumerate() {
+            match yubikey.algorithm {
+                KeyAlgorithm::EcdsaP256 => {
+                    let encoded_point = EncodedPoint::from_bytes(&yubikey.public_key)?;
+                    let verifying_key =
+                        p256::ecdsa::VerifyingKey::from_encoded_point(&encoded_point)?;
+                    let signature = p256::ecdsa::Signature::from_der(message)?;
+                    if verifying_key.verify(challenge, &signature).is_ok() {
+                        return Ok(index);
+                    }
+                }
+            }
         }
+        Err("No enrolled YubiKey made the signature".into())
+    }
+
+    /// New email verification token for `email`, in the notification carrying it.
+    fn verification(context: &Context, email: &Email) -> Result<Notification, Box<dyn Error>> {
+        log::info!("Generating the verification token");
+        let (token, expires) = context.tokens.issue(email, Purpose::Verify)?;
+        Ok(Notification::Verify { token, expires })
     }
 
-    fn send_token(to: &str, subject: &str, message: &str) -> Result<String, Box<dyn Error>> {
+    fn send_token(context: &Context, peer: IpAddr, to: &Email) -> Transition {
         log::info!("Generating the token");
-        let id = Uuid::new_v4().as_hyphenated().to_string();
-        let message = format!("{} {}", message, id);
+        let (token, expires) = context.tokens.issue(to, Purpose::Reset)?;
         log::info!("Sending token to the requested email");
-        send_mail(to, subject, &message)?;
-        Ok(id)
+        context
+            .mailer
+            .send(to, peer, &Notification::Reset { token, expires })?;
+        Ok((
+            State::Unauthenticated,
+            Response::Success(Strings::EmailSent),
+        ))
+    }
+
+    /// Second factor proven, the login is complete.
+    fn logged_in(context: &Context, peer: IpAddr, user: User) -> Transition {
+        log::info!("{}", Strings::AuthSuccess);
+        context.throttle.succeeded(&user.email)?;
+        context
+            .mailer
+            .notify(&user.email, peer, &Notification::NewLogin);
+        Ok((
+            State::Authenticated { user },
+            Response::Success(Strings::AuthSuccess),
+        ))
     }
+
+    /// Second factor not proven, which counts as a failure for `email`.
+    fn failed(context: &Context, peer: IpAddr, email: &Email, error: UtilsError) -> Transition {
+        context.throttle.failed(email, peer)?;
+        Ok((State::Unauthenticated, Response::Error(error)))
+    }
+}
+
+fn now() -> Result<u64, Box<dyn Error>> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
 }
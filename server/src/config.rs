@@ -0,0 +1,348 @@
+use crate::database::Purpose;
+use serde::Deserialize;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Server configuration, read from a RON file.
+///
+/// ```ron
+/// (
+///     address: "127.0.0.1:8080",
+///     transport: Tls(
+///         cert: "cert.pem",
+///         key: "key.pem",
+///     ),
+///     storage: Sqlite(
+///         path: "users.db",
+///     ),
+///     encryption: Some((
+///         key: File(path: "storage.key"),
+///         previous_keys: [],
+///     )),
+///     mail: (
+///         from: "Crate <noreply@example.com>",
+///         brand: "Crate",
+///         delivery: Maildir(directory: "mail"),
+///         templates: Some("templates"),
+///     ),
+///     max_sessions: 64,
+///     timeouts: (
+///         handshake: 10,
+///         read: 10,
+///         idle: 300,
+///         shutdown: 30,
+///     ),
+///     tokens: (
+///         lifetime: 900,
+///         verification_lifetime: 86400,
+///         max_attempts: 5,
+///     ),
+///     throttling: (
+///         account: (
+///             free_attempts: 3,
+///             base_delay: 1,
+///             lockout_after: 10,
+///             lockout: 900,
+///         ),
+///         address: (
+///             free_attempts: 10,
+///             base_delay: 1,
+///             lockout_after: 50,
+///             lockout: 3600,
+///         ),
+///         forget_after: 86400,
+///     ),
+///     min_response_time: 500,
+///     totp_skew: 1,
+///     recovery_codes: 10,
+///     step_up_window: 300,
+/// )
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub address: String,
+    pub transport: Transport,
+    pub storage: Storage,
+    /// Encrypts the stored users, they are kept in plaintext if `None`.
+    pub encryption: Option<Encryption>,
+    pub mail: Mail,
+    /// Clients served at the same time, the others wait to be accepted. At
+    /// least 1.
+    pub max_sessions: usize,
+    pub timeouts: Timeouts,
+    pub tokens: Tokens,
+    pub throttling: Throttling,
+    /// Milliseconds taken at least by the responses whose work depends on
+    /// whether an email has an account, so their timing does not tell.
+    pub min_response_time: u64,
+    /// Time steps before and after the current one whose TOTP codes are
+    /// still accepted, for clocks running late or early.
+    pub totp_skew: u64,
+    /// Recovery codes given at registration and at each regeneration.
+    pub recovery_codes: usize,
+    /// Seconds during which a second factor proven in a session allows its
+    /// sensitive actions, such as disabling 2FA or revoking a key.
+    pub step_up_window: u64,
+}
+
+/// Encrypted channel used to talk with the clients.
+#[derive(Deserialize, Clone, Debug)]
+pub enum Transport {
+    /// PEM encoded certificate chain and PKCS#8 private key.
+    Tls { cert: PathBuf, key: PathBuf },
+    /// Hex encoded Noise static private key, generated on first start if missing.
+    Noise { private_key: PathBuf },
+}
+
+/// Backend holding the user accounts.
+#[derive(Deserialize, Clone, Debug)]
+pub enum Storage {
+    /// Single RON file, rewritten on every change.
+    Ron { path: PathBuf },
+    /// SQLite database file.
+    Sqlite { path: PathBuf },
+    /// Kept in memory and lost on shutdown, for tests.
+    Memory,
+}
+
+/// Keys encrypting the stored users.
+///
+/// To rotate, move `key` to `previous_keys` and set a new one: the storage is
+/// re-encrypted under the new key the next time it is opened, after which the
+/// previous keys can be dropped.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Encryption {
+    pub key: Key,
+    #[serde(default)]
+    pub previous_keys: Vec<Key>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub enum Key {
+    /// Hex encoded 32 bytes key, generated on first start if missing.
+    File { path: PathBuf },
+    /// Derived with Argon2 from the passphrase in an environment variable,
+    /// `salt` is any random string of at least 8 characters.
+    Passphrase { variable: String, salt: String },
+}
+
+/// Emails sent to the users.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Mail {
+    /// Sender address, overridden by the `from` of an SMTP env file.
+    pub from: String,
+    /// Name shown in the emails.
+    pub brand: String,
+    pub delivery: Delivery,
+    /// Directory of templates replacing the built-in ones with the same file
+    /// name, see `mailer::Templates`.
+    pub templates: Option<PathBuf>,
+}
+
+/// Where the emails go.
+#[derive(Deserialize, Clone, Debug)]
+pub enum Delivery {
+    /// SMTP relay described in an env file, see `mailer::Smtp`.
+    Smtp { env: PathBuf },
+    /// One `.eml` file per email in `directory`.
+    File { directory: PathBuf },
+    /// Maildir rooted at `directory`.
+    Maildir { directory: PathBuf },
+    /// Printed on the standard output.
+    Stdout,
+}
+
+impl Default for Mail {
+    fn default() -> Self {
+        Mail {
+            from: "noreply@localhost".to_string(),
+            brand: "SEC".to_string(),
+            delivery: Delivery::Smtp {
+                env: PathBuf::from("example.env"),
+            },
+            templates: None,
+        }
+    }
+}
+
+/// Connection timeouts, in seconds.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Timeouts {
+    /// Secure channel handshake and protocol hello.
+    pub handshake: u64,
+    /// Receiving a whole request once its first bytes arrived, and sending a response.
+    pub read: u64,
+    /// Waiting for the next request.
+    pub idle: u64,
+    /// Waiting for the sessions in progress on shutdown, after which they are
+    /// dropped.
+    pub shutdown: u64,
+}
+
+impl Timeouts {
+    pub fn handshake(&self) -> Duration {
+        Duration::from_secs(self.handshake)
+    }
+
+    pub fn read(&self) -> Duration {
+        Duration::from_secs(self.read)
+    }
+
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(self.idle)
+    }
+
+    pub fn shutdown(&self) -> Duration {
+        Duration::from_secs(self.shutdown)
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            handshake: 10,
+            read: 10,
+            idle: 300,
+            shutdown: 30,
+        }
+    }
+}
+
+/// Tokens mailed to the users.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Tokens {
+    /// Validity of the password reset tokens, in seconds.
+    pub lifetime: u64,
+    /// Validity of the email verification tokens, and of the tokens reverting
+    /// an email change, in seconds.
+    pub verification_lifetime: u64,
+    /// Wrong guesses after which a token can no longer be redeemed.
+    pub max_attempts: u32,
+}
+
+impl Tokens {
+    pub fn lifetime(&self, purpose: Purpose) -> Duration {
+        Duration::from_secs(match purpose {
+            Purpose::Reset | Purpose::ChangeEmail => self.lifetime,
+            Purpose::Verify | Purpose::RevertEmail => self.verification_lifetime,
+        })
+    }
+}
+
+impl Default for Tokens {
+    fn default() -> Self {
+        Tokens {
+            lifetime: 900,
+            verification_lifetime: 86400,
+            max_attempts: 5,
+        }
+    }
+}
+
+/// Slowing down and locking out repeated authentication failures.
+///
+/// Once its free attempts are used, each failure of a subject delays its next
+/// attempt by `base_delay`, doubled for every further failure, until it is
+/// locked out. Both the account and the address must be allowed to try.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Throttling {
+    /// Per account tried, unknown emails only count against the address.
+    pub account: Limits,
+    /// Per client address, across all the emails it tries.
+    pub address: Limits,
+    /// Seconds without failures after which they are forgotten, and between
+    /// two prunings of the forgotten ones.
+    pub forget_after: u64,
+}
+
+impl Default for Throttling {
+    fn default() -> Self {
+        Throttling {
+            account: Limits {
+                free_attempts: 3,
+                base_delay: 1,
+                lockout_after: 10,
+                lockout: 900,
+            },
+            address: Limits {
+                free_attempts: 10,
+                base_delay: 1,
+                lockout_after: 50,
+                lockout: 3600,
+            },
+            forget_after: 86400,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Limits {
+    /// Failures allowed without delay.
+    pub free_attempts: u32,
+    /// Delay after the first failure beyond the free ones, in seconds.
+    pub base_delay: u64,
+    /// Failures after which the subject is locked out.
+    pub lockout_after: u32,
+    /// Lockout duration, in seconds.
+    pub lockout: u64,
+}
+
+impl Config {
+    pub fn min_response_time(&self) -> Duration {
+        Duration::from_millis(self.min_response_time)
+    }
+
+    pub fn step_up_window(&self) -> Duration {
+        Duration::from_secs(self.step_up_window)
+    }
+
+    /// Loads the configuration, falling back to the defaults if the file is missing.
+    pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+        if !path.exists() {
+            log::warn!("No configuration at {}, using defaults", path.display());
+            return Ok(Config::default());
+        }
+        let config: Config = ron::from_str(&std::fs::read_to_string(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects the values the server cannot run with.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.max_sessions == 0 {
+            return Err("max_sessions must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "127.0.0.1:8080".to_string(),
+            transport: Transport::Tls {
+                cert: PathBuf::from("cert.pem"),
+                key: PathBuf::from("key.pem"),
+            },
+            storage: Storage::Ron {
+                path: PathBuf::from("db.ron"),
+            },
+            encryption: None,
+            mail: Mail::default(),
+            max_sessions: 64,
+            timeouts: Timeouts::default(),
+            tokens: Tokens::default(),
+            throttling: Throttling::default(),
+            min_response_time: 500,
+            totp_skew: 1,
+            recovery_codes: 10,
+            step_up_window: 300,
+        }
+    }
+}
@@ -1,20 +1,178 @@
-use std::net::{TcpStream};
-use serde::Serialize;
+use crate::config::{Timeouts, Transport};
+use crate::key_file;
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection, StreamOwned};
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use utils::frame::{read_frame, write_frame, PROTOCOL_VERSION};
+use utils::noise::{generate_keypair, NoiseStream};
+use utils::{Error as UtilsError, HelloData};
+
+trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
 
 pub struct Connection {
-    stream: TcpStream
+    stream: Box<dyn Stream>,
+    timer: Arc<Mutex<Timer>>,
+}
+
+/// Time left to the peer for its next bytes.
+#[derive(Clone, Copy)]
+enum Timer {
+    /// Waiting for a new request, which may take up to the idle timeout.
+    Idle,
+    /// A request or handshake started and must be fully received in time.
+    Until(Instant),
+}
+
+/// Socket under the encrypted channel, enforcing the timeouts on every read.
+struct TimedStream {
+    socket: TcpStream,
+    timer: Arc<Mutex<Timer>>,
+    timeouts: Timeouts,
+}
+
+/// Server side of the configured transport, shared by every connection.
+#[derive(Clone)]
+pub enum Acceptor {
+    Tls(Arc<ServerConfig>),
+    Noise(Arc<Vec<u8>>),
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream) -> Connection { Connection{stream} }
+    /// Wraps an accepted socket in the configured encrypted channel and
+    /// checks that the client speaks the same protocol version.
+    pub fn new(
+        socket: TcpStream,
+        acceptor: &Acceptor,
+        timeouts: &Timeouts,
+    ) -> Result<Connection, Box<dyn Error>> {
+        socket.set_write_timeout(Some(timeouts.read()))?;
+        let timer = Arc::new(Mutex::new(Timer::Until(
+            Instant::now() + timeouts.handshake(),
+        )));
+        let stream = TimedStream {
+            socket,
+            timer: timer.clone(),
+            timeouts: timeouts.clone(),
+        };
+
+        let stream: Box<dyn Stream> = match acceptor {
+            Acceptor::Tls(tls) => Box::new(StreamOwned::new(
+                ServerConnection::new(tls.clone())?,
+                stream,
+            )),
+            Acceptor::Noise(private_key) => Box::new(NoiseStream::accept(stream, private_key)?),
+        };
+        let mut connection = Connection { stream, timer };
+        connection.hello()?;
+        Ok(connection)
+    }
+
+    fn hello(&mut self) -> Result<(), Box<dyn Error>> {
+        let hello: HelloData = self.receive()?;
+        self.send(&HelloData {
+            version: PROTOCOL_VERSION,
+        })?;
+        if hello.version != PROTOCOL_VERSION {
+            return Err(UtilsError::IncompatibleVersion(hello.version).into());
+        }
+        Ok(())
+    }
+
+    pub fn send<T>(&mut self, o: &T) -> Result<(), Box<dyn Error>>
+    where
+        T: Serialize,
+    {
+        write_frame(&mut self.stream, o)
+    }
+
+    pub fn receive<T>(&mut self) -> Result<T, Box<dyn Error>>
+    where
+        T: DeserializeOwned,
+    {
+        let o = read_frame(&mut self.stream)?;
+        *self.timer.lock().unwrap() = Timer::Idle;
+        Ok(o)
+    }
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timer = *self.timer.lock().unwrap();
+        let timeout = match timer {
+            Timer::Idle => self.timeouts.idle(),
+            Timer::Until(deadline) => deadline
+                .checked_duration_since(Instant::now())
+                .filter(|timeout| !timeout.is_zero())
+                .ok_or(io::ErrorKind::TimedOut)?,
+        };
+        self.socket.set_read_timeout(Some(timeout))?;
 
-    pub fn send<T>(&mut self, o: &T) -> Result<(), Box<dyn Error>> where T: Serialize {
-        Ok(bincode::serialize_into(&self.stream, &o)?)
+        let len = match self.socket.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(io::ErrorKind::TimedOut.into()),
+            result => result,
+        }?;
+        if let Timer::Idle = timer {
+            *self.timer.lock().unwrap() = Timer::Until(Instant::now() + self.timeouts.read());
+        }
+        Ok(len)
     }
+}
+
+impl Write for TimedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
 
-    pub fn receive<T>(&mut self) -> Result<T, Box<dyn Error>> where T: DeserializeOwned {
-        Ok(bincode::deserialize_from(&self.stream)?)
+impl Acceptor {
+    pub fn new(transport: &Transport) -> Result<Acceptor, Box<dyn Error>> {
+        Ok(match transport {
+            Transport::Tls { cert, key } => Acceptor::Tls(tls_config(cert, key)?),
+            Transport::Noise { private_key } => Acceptor::Noise(Arc::new(noise_key(private_key)?)),
+        })
     }
-}
\ No newline at end of file
+}
+
+fn tls_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key)?))?
+        .into_iter()
+        .next()
+        .ok_or("No PKCS#8 private key found")?;
+
+    Ok(Arc::new(
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, PrivateKey(key))?,
+    ))
+}
+
+/// Reads the Noise static key, generating and saving a new one if missing.
+fn noise_key(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    if path.exists() {
+        return Ok(hex::decode(key_file::read(path)?.trim())?);
+    }
+
+    log::warn!("No Noise key at {}, generating one", path.display());
+    let (private_key, public_key) = generate_keypair()?;
+    key_file::create(path, &hex::encode(&private_key))?;
+    log::info!("Noise public key to pin: {}", hex::encode(public_key));
+    Ok(private_key)
+}
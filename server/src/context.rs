@@ -0,0 +1,20 @@
+use crate::config::Config;
+use crate::database::UserStore;
+use crate::mailer::Mailer;
+use crate::recovery::RecoveryCodes;
+use crate::throttle::Throttle;
+use crate::tokens::Tokens;
+use crate::totp::Totp;
+use std::sync::Arc;
+
+/// Services shared by all the sessions.
+#[derive(Clone)]
+pub struct Context {
+    pub store: Arc<dyn UserStore>,
+    pub mailer: Arc<Mailer>,
+    pub tokens: Arc<Tokens>,
+    pub throttle: Arc<Throttle>,
+    pub totp: Arc<Totp>,
+    pub recovery: Arc<RecoveryCodes>,
+    pub config: Arc<Config>,
+}
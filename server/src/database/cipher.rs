@@ -0,0 +1,214 @@
+use crate::config::{Encryption, Key};
+use crate::key_file;
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rustbreak::backend::Backend;
+use rustbreak::error::{BackendError, BackendResult};
+use sha2::Sha256;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Prefix of the sealed data, followed by the key id, the nonce and the
+/// XChaCha20-Poly1305 ciphertext.
+const MAGIC: &[u8] = b"SEAL";
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 24;
+
+/// One storage key and the subkeys derived from it.
+struct SealingKey {
+    id: [u8; KEY_ID_LEN],
+    aead: XChaCha20Poly1305,
+    index: Vec<u8>,
+}
+
+impl SealingKey {
+    fn new(master: &[u8]) -> Result<SealingKey, Box<dyn Error>> {
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&derive(master, b"key id")?[..KEY_ID_LEN]);
+        Ok(SealingKey {
+            id,
+            aead: XChaCha20Poly1305::new_from_slice(&derive(master, b"encryption")?)
+                .map_err(|e| e.to_string())?,
+            index: derive(master, b"index")?,
+        })
+    }
+
+    fn load(key: &Key, generate: bool) -> Result<SealingKey, Box<dyn Error>> {
+        let master = match key {
+            Key::File { path } => key_file(path, generate)?,
+            Key::Passphrase { variable, salt } => {
+                let passphrase = std::env::var(variable)
+                    .map_err(|_| format!("Storage passphrase variable {} is not set", variable))?;
+                let mut master = vec![0; 32];
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut master)
+                    .map_err(|e| e.to_string())?;
+                master
+            }
+        };
+        SealingKey::new(&master)
+    }
+}
+
+/// Encrypts the stored users with the current key, and decrypts them with any
+/// of the configured keys. Without keys, data is left in plaintext.
+pub struct Cipher {
+    keys: Vec<SealingKey>,
+}
+
+impl Cipher {
+    pub fn new(encryption: Option<&Encryption>) -> Result<Cipher, Box<dyn Error>> {
+        Cipher::load(encryption, false)
+    }
+
+    /// Same as `new`, but generates the current key file if it is missing.
+    pub fn create(encryption: Option<&Encryption>) -> Result<Cipher, Box<dyn Error>> {
+        Cipher::load(encryption, true)
+    }
+
+    fn load(encryption: Option<&Encryption>, generate: bool) -> Result<Cipher, Box<dyn Error>> {
+        let Some(encryption) = encryption else {
+            return Ok(Cipher { keys: vec![] });
+        };
+
+        let mut keys = vec![SealingKey::load(&encryption.key, generate)?];
+        for key in &encryption.previous_keys {
+            keys.push(SealingKey::load(key, false)?);
+        }
+        Ok(Cipher { keys })
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let Some(key) = self.keys.first() else {
+            return Ok(plaintext.to_vec());
+        };
+
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let header = [MAGIC, &key.id].concat();
+        let ciphertext = key
+            .aead
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &header,
+                },
+            )
+            .map_err(|e| e.to_string())?;
+        Ok([header.as_slice(), &nonce, &ciphertext].concat())
+    }
+
+    /// Decrypts sealed data, plaintext is returned as is so that existing
+    /// storage can be encrypted when it is opened.
+    pub fn unseal(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let Some(sealed) = data.strip_prefix(MAGIC) else {
+            return Ok(data.to_vec());
+        };
+        if sealed.len() < KEY_ID_LEN + NONCE_LEN {
+            return Err("Sealed storage data is truncated".into());
+        }
+
+        let (id, sealed) = sealed.split_at(KEY_ID_LEN);
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == id)
+            .ok_or("Storage data is sealed with an unknown key")?;
+        let plaintext = key
+            .aead
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &data[..MAGIC.len() + KEY_ID_LEN],
+                },
+            )
+            .map_err(|_| "Storage data failed to decrypt")?;
+        Ok(plaintext)
+    }
+
+    /// Whether `data` is already sealed the way `seal` would do it now.
+    pub fn is_current(&self, data: &[u8]) -> bool {
+        match self.keys.first() {
+            Some(key) => data
+                .strip_prefix(MAGIC)
+                .is_some_and(|sealed| sealed.len() >= KEY_ID_LEN && sealed[..KEY_ID_LEN] == key.id),
+            None => !data.starts_with(MAGIC),
+        }
+    }
+
+    /// Lookup key of a user, keyed with the current key so that it does not
+    /// reveal the email.
+    pub fn index(&self, email: &str) -> Result<String, Box<dyn Error>> {
+        match self.keys.first() {
+            Some(key) => Ok(hex::encode(derive(&key.index, email.as_bytes())?)),
+            None => Ok(email.to_string()),
+        }
+    }
+}
+
+/// Backend sealing everything written to the inner one.
+pub struct SealedBackend<B: Backend> {
+    backend: B,
+    cipher: Arc<Cipher>,
+}
+
+impl<B: Backend> SealedBackend<B> {
+    pub fn new(backend: B, cipher: Arc<Cipher>) -> SealedBackend<B> {
+        SealedBackend { backend, cipher }
+    }
+
+    /// Reads the stored data, also telling whether it must be sealed again.
+    pub fn read(&mut self) -> Result<(Vec<u8>, bool), Box<dyn Error>> {
+        let data = self.backend.get_data()?;
+        Ok((self.cipher.unseal(&data)?, self.cipher.is_current(&data)))
+    }
+}
+
+impl<B: Backend> Backend for SealedBackend<B> {
+    fn get_data(&mut self) -> BackendResult<Vec<u8>> {
+        let data = self.backend.get_data()?;
+        self.cipher
+            .unseal(&data)
+            .map_err(|e| BackendError::Internal(e.to_string()))
+    }
+
+    fn put_data(&mut self, data: &[u8]) -> BackendResult<()> {
+        let sealed = self
+            .cipher
+            .seal(data)
+            .map_err(|e| BackendError::Internal(e.to_string()))?;
+        self.backend.put_data(&sealed)
+    }
+}
+
+fn derive(key: &[u8], label: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+    mac.update(label);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+fn key_file(path: &Path, generate: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !path.exists() && !generate {
+        return Err(format!("Storage key {} is missing", path.display()).into());
+    }
+    if path.exists() {
+        let key = hex::decode(key_file::read(path)?.trim())?;
+        if key.len() != 32 {
+            return Err(format!("Storage key {} is not 32 bytes long", path.display()).into());
+        }
+        return Ok(key);
+    }
+
+    log::warn!("No storage key at {}, generating one", path.display());
+    let mut key = vec![0; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    key_file::create(path, &hex::encode(&key))?;
+    Ok(key)
+}
@@ -0,0 +1,172 @@
+mod cipher;
+mod ron_store;
+mod schema;
+mod sqlite_store;
+
+use crate::config::{Encryption, Storage};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use utils::User;
+use validation::Email;
+
+pub use cipher::Cipher;
+pub use ron_store::RonStore;
+pub use schema::SCHEMA_VERSION;
+pub use sqlite_store::SqliteStore;
+
+/// Persistent storage of the user accounts, keyed by email.
+pub trait UserStore: Send + Sync {
+    fn get(&self, email: &Email) -> Result<Option<User>, Box<dyn Error>>;
+
+    /// Adds a new user, failing if the email is already taken.
+    fn insert(&self, user: &User) -> Result<(), Box<dyn Error>>;
+
+    /// Applies `change` to the user stored under `email` and stores it in one
+    /// step, so that concurrent changes to the same user are never lost.
+    /// `change` keeps the email, and returns false to leave the user as it is.
+    /// Returns the user changed, none if there is no user under `email` or
+    /// `change` declined.
+    fn modify(
+        &self,
+        email: &Email,
+        change: &mut dyn FnMut(&mut User) -> bool,
+    ) -> Result<Option<User>, Box<dyn Error>>;
+
+    /// Moves the user stored under `email` to `new_email` in one step,
+    /// dropping the tokens of `email`, and returns it. Fails if the new email
+    /// is taken or no user is stored under `email`.
+    fn rekey(&self, email: &Email, new_email: &Email) -> Result<User, Box<dyn Error>>;
+
+    /// Removes a user with its failures and the tokens issued to it or moving
+    /// it, returning whether it existed.
+    fn delete(&self, email: &Email) -> Result<bool, Box<dyn Error>>;
+
+    fn list(&self) -> Result<Vec<User>, Box<dyn Error>>;
+
+    fn get_token(
+        &self,
+        email: &Email,
+        purpose: Purpose,
+    ) -> Result<Option<StoredToken>, Box<dyn Error>>;
+
+    /// Adds a token, replacing the previous one with the same email and purpose.
+    fn put_token(&self, token: &StoredToken) -> Result<(), Box<dyn Error>>;
+
+    fn get_failures(&self, subject: &Subject) -> Result<Option<Failures>, Box<dyn Error>>;
+
+    /// Adds failures, replacing the previous ones of the same subject.
+    fn put_failures(&self, failures: &Failures) -> Result<(), Box<dyn Error>>;
+
+    /// Forgets the failures of `subject`, returning whether there were any.
+    fn clear_failures(&self, subject: &Subject) -> Result<bool, Box<dyn Error>>;
+
+    /// Forgets the failures for which `expired` holds, returning how many.
+    fn prune_failures(&self, expired: &dyn Fn(&Failures) -> bool) -> Result<usize, Box<dyn Error>>;
+}
+
+/// What a mailed token allows its holder to do.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Purpose {
+    Reset,
+    /// Confirming the email of a new account.
+    Verify,
+    /// Moving an account to its `target`, the email the token is mailed to.
+    ChangeEmail,
+    /// Moving an account at `target` back to the email the token is mailed to.
+    RevertEmail,
+}
+
+impl Purpose {
+    pub const ALL: [Purpose; 4] = [
+        Purpose::Reset,
+        Purpose::Verify,
+        Purpose::ChangeEmail,
+        Purpose::RevertEmail,
+    ];
+}
+
+/// Token mailed to a user, only its hash is stored.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredToken {
+    pub email: Email,
+    pub purpose: Purpose,
+    /// SHA-256 of the token.
+    pub hash: Vec<u8>,
+    /// Seconds since the Unix epoch.
+    pub expires: u64,
+    /// Failed redemptions so far.
+    pub attempts: u32,
+    pub used: bool,
+    /// Email of the account moved by the email change tokens.
+    #[serde(default)]
+    pub target: Option<Email>,
+}
+
+/// Opens the storage backend selected in the configuration, migrating the
+/// stored users to the current schema and sealing them with the current key.
+pub fn open(
+    storage: &Storage,
+    encryption: Option<&Encryption>,
+) -> Result<Arc<dyn UserStore>, Box<dyn Error>> {
+    let cipher = Arc::new(Cipher::create(encryption)?);
+    Ok(match storage {
+        Storage::Ron { path } => Arc::new(RonStore::open(path, cipher)?),
+        Storage::Sqlite { path } => Arc::new(SqliteStore::open(path, cipher)?),
+        Storage::Memory => Arc::new(RonStore::memory()?),
+    })
+}
+
+/// What authentication failures are counted against.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Subject {
+    /// Account tried, by its email.
+    Account(Email),
+    /// Client address, across all the emails it tries.
+    Address(IpAddr),
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::Account(email) => write!(f, "account:{}", email.as_str()),
+            Subject::Address(address) => write!(f, "address:{}", address),
+        }
+    }
+}
+
+/// Recent authentication failures of a subject.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Failures {
+    pub subject: Subject,
+    /// Failures since the last success, lockout or quiet period.
+    pub count: u32,
+    /// Seconds since the Unix epoch of the last failure.
+    pub last: u64,
+    /// Seconds since the Unix epoch before which no attempt is allowed.
+    pub blocked_until: u64,
+}
+
+/// State of the stored users.
+pub struct Status {
+    pub version: u32,
+    /// Whether they are sealed with the current key, or in plaintext if
+    /// encryption is disabled.
+    pub sealed: bool,
+}
+
+/// Reads the state of the stored users without changing them, `None` if
+/// nothing is stored yet.
+pub fn status(
+    storage: &Storage,
+    encryption: Option<&Encryption>,
+) -> Result<Option<Status>, Box<dyn Error>> {
+    let cipher = Cipher::new(encryption)?;
+    match storage {
+        Storage::Ron { path } => RonStore::status(path, &cipher),
+        Storage::Sqlite { path } => SqliteStore::status(path, &cipher),
+        Storage::Memory => Ok(None),
+    }
+}
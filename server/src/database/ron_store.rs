@@ -0,0 +1,257 @@
+use super::cipher::{Cipher, SealedBackend};
+use super::schema::{self, SCHEMA_VERSION};
+use super::{Failures, Purpose, Status, StoredToken, Subject, UserStore};
+use rustbreak::backend::{Backend, FileBackend, MemoryBackend};
+use rustbreak::{deser::Ron, Database, MemoryDatabase};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use utils::{Error as UtilsError, User};
+use validation::Email;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Users {
+    version: u32,
+    data: HashMap<Email, User>,
+    #[serde(default)]
+    tokens: Vec<StoredToken>,
+    #[serde(default)]
+    failures: Vec<Failures>,
+}
+
+impl Default for Users {
+    fn default() -> Self {
+        Users {
+            version: SCHEMA_VERSION,
+            data: HashMap::new(),
+            tokens: vec![],
+            failures: vec![],
+        }
+    }
+}
+
+/// Records of a stored document besides the users, which are missing from
+/// the documents written before they were introduced.
+#[derive(Deserialize)]
+struct Extras {
+    #[serde(default)]
+    tokens: Vec<StoredToken>,
+    #[serde(default)]
+    failures: Vec<Failures>,
+}
+
+/// Users kept in a single RON document, rewritten on every change.
+pub struct RonStore<B: Backend> {
+    db: Database<Users, B, Ron>,
+}
+
+impl RonStore<SealedBackend<FileBackend>> {
+    /// Opens the document, upgrading it to the current schema and sealing it
+    /// with the current key if needed.
+    ///
+    /// The previous version is kept next to it as `<path>.v<version>.bak`.
+    /// A version 0 backup holds the Argon2 password hashes, which now log in
+    /// like the passwords themselves: it must be deleted once the migration
+    /// is checked.
+    pub fn open(path: &Path, cipher: Arc<Cipher>) -> Result<Self, Box<dyn Error>> {
+        let (backend, exists) = FileBackend::from_path_or_create(path)?;
+        let mut backend = SealedBackend::new(backend, cipher.clone());
+        if !exists {
+            let db = Database::from_parts(Users::default(), backend, Ron);
+            db.save()?;
+            return Ok(RonStore { db });
+        }
+
+        let (document, sealed) = backend.read()?;
+        let document = String::from_utf8(document)?;
+        let version = schema::document_version(&document)?;
+        let extras: Extras = ron::from_str(&document)?;
+        let users = Users {
+            data: schema::decode_document(&document)?,
+            tokens: extras.tokens,
+            failures: extras.failures,
+            ..Users::default()
+        };
+        let db = Database::from_parts(users, backend, Ron);
+
+        if version < SCHEMA_VERSION {
+            log::warn!(
+                "Migrating {} from schema version {} to {}",
+                path.display(),
+                version,
+                SCHEMA_VERSION
+            );
+            let backup = format!("{}.v{}.bak", path.display(), version);
+            fs::write(&backup, cipher.seal(document.as_bytes())?)?;
+            if version == 0 {
+                log::warn!(
+                    "{} holds password hashes usable to log in, delete it once the migration is checked",
+                    backup
+                );
+            }
+        }
+        if !sealed {
+            log::warn!("Sealing {} with the current key", path.display());
+        }
+        if version < SCHEMA_VERSION || !sealed {
+            db.save()?;
+        }
+        Ok(RonStore { db })
+    }
+
+    pub fn status(path: &Path, cipher: &Cipher) -> Result<Option<Status>, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read(path)?;
+        let document = String::from_utf8(cipher.unseal(&data)?)?;
+        Ok(Some(Status {
+            version: schema::document_version(&document)?,
+            sealed: cipher.is_current(&data),
+        }))
+    }
+}
+
+impl RonStore<MemoryBackend> {
+    pub fn memory() -> Result<Self, Box<dyn Error>> {
+        Ok(RonStore {
+            db: MemoryDatabase::memory(Users::default())?,
+        })
+    }
+}
+
+impl<B: Backend + Send> UserStore for RonStore<B> {
+    fn get(&self, email: &Email) -> Result<Option<User>, Box<dyn Error>> {
+        Ok(self.db.borrow_data()?.data.get(email).cloned())
+    }
+
+    fn insert(&self, user: &User) -> Result<(), Box<dyn Error>> {
+        self.db.write(|db| {
+            if db.data.contains_key(&user.email) {
+                return Err(UtilsError::UserAlreadyExist);
+            }
+            db.data.insert(user.email.clone(), user.clone());
+            Ok(())
+        })??;
+        Ok(self.db.save()?)
+    }
+
+    fn modify(
+        &self,
+        email: &Email,
+        change: &mut dyn FnMut(&mut User) -> bool,
+    ) -> Result<Option<User>, Box<dyn Error>> {
+        let modified = self.db.write(|db| {
+            let mut user = db.data.get(email)?.clone();
+            if !change(&mut user) {
+                return None;
+            }
+            db.data.insert(email.clone(), user.clone());
+            Some(user)
+        })?;
+        if modified.is_some() {
+            self.db.save()?;
+        }
+        Ok(modified)
+    }
+
+    fn rekey(&self, email: &Email, new_email: &Email) -> Result<User, Box<dyn Error>> {
+        let user = self.db.write(|db| {
+            if db.data.contains_key(new_email) {
+                return Err(UtilsError::UserAlreadyExist);
+            }
+            let Some(mut user) = db.data.remove(email) else {
+                return Err(UtilsError::InvalidEmail);
+            };
+            db.tokens.retain(|token| &token.email != email);
+            user.email = new_email.clone();
+            db.data.insert(new_email.clone(), user.clone());
+            Ok(user)
+        })??;
+        self.db.save()?;
+        Ok(user)
+    }
+
+    fn delete(&self, email: &Email) -> Result<bool, Box<dyn Error>> {
+        let deleted = self.db.write(|db| {
+            db.tokens
+                .retain(|token| &token.email != email && token.target.as_ref() != Some(email));
+            let account = Subject::Account(email.clone());
+            db.failures.retain(|failures| failures.subject != account);
+            db.data.remove(email).is_some()
+        })?;
+        self.db.save()?;
+        Ok(deleted)
+    }
+
+    fn list(&self) -> Result<Vec<User>, Box<dyn Error>> {
+        Ok(self.db.borrow_data()?.data.values().cloned().collect())
+    }
+
+    fn get_token(
+        &self,
+        email: &Email,
+        purpose: Purpose,
+    ) -> Result<Option<StoredToken>, Box<dyn Error>> {
+        Ok(self
+            .db
+            .borrow_data()?
+            .tokens
+            .iter()
+            .find(|token| &token.email == email && token.purpose == purpose)
+            .cloned())
+    }
+
+    fn put_token(&self, token: &StoredToken) -> Result<(), Box<dyn Error>> {
+        self.db.write(|db| {
+            db.tokens
+                .retain(|stored| stored.email != token.email || stored.purpose != token.purpose);
+            db.tokens.push(token.clone());
+        })?;
+        Ok(self.db.save()?)
+    }
+
+    fn get_failures(&self, subject: &Subject) -> Result<Option<Failures>, Box<dyn Error>> {
+        Ok(self
+            .db
+            .borrow_data()?
+            .failures
+            .iter()
+            .find(|failures| &failures.subject == subject)
+            .cloned())
+    }
+
+    fn put_failures(&self, failures: &Failures) -> Result<(), Box<dyn Error>> {
+        self.db.write(|db| {
+            db.failures
+                .retain(|stored| stored.subject != failures.subject);
+            db.failures.push(failures.clone());
+        })?;
+        Ok(self.db.save()?)
+    }
+
+    fn clear_failures(&self, subject: &Subject) -> Result<bool, Box<dyn Error>> {
+        let cleared = self.db.write(|db| {
+            let count = db.failures.len();
+            db.failures.retain(|failures| &failures.subject != subject);
+            db.failures.len() < count
+        })?;
+        self.db.save()?;
+        Ok(cleared)
+    }
+
+    fn prune_failures(&self, expired: &dyn Fn(&Failures) -> bool) -> Result<usize, Box<dyn Error>> {
+        let pruned = self.db.write(|db| {
+            let count = db.failures.len();
+            db.failures.retain(|failures| !expired(failures));
+            count - db.failures.len()
+        })?;
+        if pruned > 0 {
+            self.db.save()?;
+        }
+        Ok(pruned)
+    }
+}
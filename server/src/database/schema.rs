@@ -0,0 +1,227 @@
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+use utils::{crypto::srp_verifier_from_hash, KeyAlgorithm, KeyCredential, TotpCredential, User};
+use validation::Email;
+
+/// Version of the user records written by this build.
+///
+/// Bump it whenever `User` changes, keep the previous layout below as
+/// `UserV<n>` and upgrade it to the next version.
+pub const SCHEMA_VERSION: u32 = 5;
+
+/// Start of a stored RON document, the files written before versioning have
+/// no `version` field.
+#[derive(Deserialize)]
+struct Header {
+    #[serde(default)]
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct Document<U> {
+    data: HashMap<Email, U>,
+}
+
+/// Record layout of a schema version, upgraded one version at a time.
+trait Upgrade: DeserializeOwned {
+    fn upgrade(self) -> Result<User, Box<dyn Error>>;
+}
+
+impl Upgrade for User {
+    fn upgrade(self) -> Result<User, Box<dyn Error>> {
+        Ok(self)
+    }
+}
+
+/// Version 4: a single unnamed YubiKey.
+#[derive(Deserialize)]
+struct UserV4 {
+    email: Email,
+    salt: String,
+    verifier: Vec<u8>,
+    two_f_a: bool,
+    yubikey: Vec<u8>,
+    totp: Option<TotpCredential>,
+    recovery_codes: Vec<Vec<u8>>,
+    verified: bool,
+}
+
+impl Upgrade for UserV4 {
+    /// The key is dated from the upgrade, its enrollment time is unknown.
+    fn upgrade(self) -> Result<User, Box<dyn Error>> {
+        let mut yubikeys = vec![];
+        if !self.yubikey.is_empty() {
+            yubikeys.push(KeyCredential {
+                label: "YubiKey".parse().unwrap(),
+                public_key: self.yubikey,
+                algorithm: KeyAlgorithm::EcdsaP256,
+                created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                last_used: None,
+            });
+        }
+        User {
+            email: self.email,
+            salt: self.salt,
+            verifier: self.verifier,
+            two_f_a: self.two_f_a,
+            yubikeys,
+            totp: self.totp,
+            recovery_codes: self.recovery_codes,
+            verified: self.verified,
+        }
+        .upgrade()
+    }
+}
+
+/// Version 3: no recovery codes.
+#[derive(Deserialize)]
+struct UserV3 {
+    email: Email,
+    salt: String,
+    verifier: Vec<u8>,
+    two_f_a: bool,
+    yubikey: Vec<u8>,
+    totp: Option<TotpCredential>,
+    verified: bool,
+}
+
+impl Upgrade for UserV3 {
+    fn upgrade(self) -> Result<User, Box<dyn Error>> {
+        UserV4 {
+            email: self.email,
+            salt: self.salt,
+            verifier: self.verifier,
+            two_f_a: self.two_f_a,
+            yubikey: self.yubikey,
+            totp: self.totp,
+            recovery_codes: vec![],
+            verified: self.verified,
+        }
+        .upgrade()
+    }
+}
+
+/// Version 2: YubiKey as the only second factor.
+#[derive(Deserialize)]
+struct UserV2 {
+    email: Email,
+    salt: String,
+    verifier: Vec<u8>,
+    two_f_a: bool,
+    yubikey: Vec<u8>,
+    verified: bool,
+}
+
+impl Upgrade for UserV2 {
+    fn upgrade(self) -> Result<User, Box<dyn Error>> {
+        UserV3 {
+            email: self.email,
+            salt: self.salt,
+            verifier: self.verifier,
+            two_f_a: self.two_f_a,
+            yubikey: self.yubikey,
+            totp: None,
+            verified: self.verified,
+        }
+        .upgrade()
+    }
+}
+
+/// Version 1: accounts usable without confirming their email.
+#[derive(Deserialize)]
+struct UserV1 {
+    email: Email,
+    salt: String,
+    verifier: Vec<u8>,
+    two_f_a: bool,
+    yubikey: Vec<u8>,
+}
+
+impl Upgrade for UserV1 {
+    fn upgrade(self) -> Result<User, Box<dyn Error>> {
+        UserV2 {
+            email: self.email,
+            salt: self.salt,
+            verifier: self.verifier,
+            two_f_a: self.two_f_a,
+            yubikey: self.yubikey,
+            verified: true,
+        }
+        .upgrade()
+    }
+}
+
+/// Version 0: Argon2 password hash checked with an HMAC challenge.
+#[derive(Deserialize)]
+struct UserV0 {
+    email: Email,
+    salt: String,
+    hash_password: String,
+    two_f_a: bool,
+    yubikey: Vec<u8>,
+}
+
+impl Upgrade for UserV0 {
+    fn upgrade(self) -> Result<User, Box<dyn Error>> {
+        UserV1 {
+            verifier: srp_verifier_from_hash(&self.hash_password, &self.salt),
+            email: self.email,
+            salt: self.salt,
+            two_f_a: self.two_f_a,
+            yubikey: self.yubikey,
+        }
+        .upgrade()
+    }
+}
+
+/// Reads the schema version of a RON document.
+pub fn document_version(document: &str) -> Result<u32, Box<dyn Error>> {
+    Ok(ron::from_str::<Header>(document)?.version)
+}
+
+/// Reads a RON document of any known version, upgrading its users.
+pub fn decode_document(document: &str) -> Result<HashMap<Email, User>, Box<dyn Error>> {
+    match supported(document_version(document)?)? {
+        0 => upgrade_document::<UserV0>(document),
+        1 => upgrade_document::<UserV1>(document),
+        2 => upgrade_document::<UserV2>(document),
+        3 => upgrade_document::<UserV3>(document),
+        4 => upgrade_document::<UserV4>(document),
+        _ => upgrade_document::<User>(document),
+    }
+}
+
+/// Reads a single user record stored at `version`, upgrading it.
+pub fn decode_record(version: u32, record: &str) -> Result<User, Box<dyn Error>> {
+    match supported(version)? {
+        0 => ron::from_str::<UserV0>(record)?.upgrade(),
+        1 => ron::from_str::<UserV1>(record)?.upgrade(),
+        2 => ron::from_str::<UserV2>(record)?.upgrade(),
+        3 => ron::from_str::<UserV3>(record)?.upgrade(),
+        4 => ron::from_str::<UserV4>(record)?.upgrade(),
+        _ => ron::from_str::<User>(record)?.upgrade(),
+    }
+}
+
+fn upgrade_document<U: Upgrade>(document: &str) -> Result<HashMap<Email, User>, Box<dyn Error>> {
+    ron::from_str::<Document<U>>(document)?
+        .data
+        .into_iter()
+        .map(|(email, user)| Ok((email, user.upgrade()?)))
+        .collect()
+}
+
+/// Fails if `version` was written by a newer build.
+pub fn supported(version: u32) -> Result<u32, Box<dyn Error>> {
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "Storage schema version {} is newer than the supported version {}",
+            version, SCHEMA_VERSION
+        )
+        .into());
+    }
+    Ok(version)
+}
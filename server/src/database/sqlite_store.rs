@@ -0,0 +1,421 @@
+use super::cipher::Cipher;
+use super::schema::{self, SCHEMA_VERSION};
+use super::{Failures, Purpose, Status, StoredToken, Subject, UserStore};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use utils::{Error as UtilsError, User};
+use validation::Email;
+
+/// Users kept in a SQLite table, one RON encoded record per row, and their
+/// tokens and failures in two others.
+///
+/// Lookups go through the index of the `email` primary key, and every change
+/// runs in its own transaction so the file is never left half written. With
+/// encryption, the records are sealed and the emails replaced by their keyed
+/// hash.
+pub struct SqliteStore {
+    db: Mutex<Connection>,
+    cipher: Arc<Cipher>,
+}
+
+impl SqliteStore {
+    /// Opens the database, upgrading its records to the current schema and
+    /// sealing them with the current key if needed. The schema version is kept
+    /// in `PRAGMA user_version`.
+    pub fn open(path: &Path, cipher: Arc<Cipher>) -> Result<Self, Box<dyn Error>> {
+        let mut db = Connection::open(path)?;
+        db.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS tokens (
+                email TEXT NOT NULL,
+                purpose TEXT NOT NULL,
+                record BLOB NOT NULL,
+                PRIMARY KEY (email, purpose)
+            );
+            CREATE TABLE IF NOT EXISTS failures (
+                subject TEXT PRIMARY KEY NOT NULL,
+                record BLOB NOT NULL
+            );",
+        )?;
+
+        if let Some(version) = SqliteStore::version(&db)? {
+            schema::supported(version)?;
+            let sealed = SqliteStore::sealed(&db, &cipher)?;
+
+            if version < SCHEMA_VERSION {
+                log::warn!(
+                    "Migrating {} from schema version {} to {}",
+                    path.display(),
+                    version,
+                    SCHEMA_VERSION
+                );
+            }
+            if !sealed {
+                log::warn!("Sealing {} with the current key", path.display());
+            }
+            if version < SCHEMA_VERSION || !sealed {
+                SqliteStore::rewrite(&mut db, &cipher, version)?;
+            }
+        } else {
+            db.execute_batch(&format!(
+                "CREATE TABLE users (
+                    email TEXT PRIMARY KEY NOT NULL,
+                    record BLOB NOT NULL
+                );
+                PRAGMA user_version = {};",
+                SCHEMA_VERSION
+            ))?;
+        }
+
+        Ok(SqliteStore {
+            db: Mutex::new(db),
+            cipher,
+        })
+    }
+
+    pub fn status(path: &Path, cipher: &Cipher) -> Result<Option<Status>, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let Some(version) = SqliteStore::version(&db)? else {
+            return Ok(None);
+        };
+        Ok(Some(Status {
+            version,
+            sealed: SqliteStore::sealed(&db, cipher)?,
+        }))
+    }
+
+    fn version(db: &Connection) -> Result<Option<u32>, Box<dyn Error>> {
+        let tables: u32 = db.query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'users'",
+            [],
+            |row| row.get(0),
+        )?;
+        if tables == 0 {
+            return Ok(None);
+        }
+        Ok(Some(
+            db.query_row("PRAGMA user_version", [], |row| row.get(0))?,
+        ))
+    }
+
+    /// Records are always rewritten all together, checking one is enough.
+    fn sealed(db: &Connection, cipher: &Cipher) -> Result<bool, Box<dyn Error>> {
+        Ok(db
+            .query_row(
+                "SELECT CAST(record AS BLOB) FROM users
+                UNION ALL SELECT CAST(record AS BLOB) FROM tokens
+                UNION ALL SELECT CAST(record AS BLOB) FROM failures
+                LIMIT 1",
+                [],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?
+            .is_none_or(|record| cipher.is_current(&record)))
+    }
+
+    /// Rewrites every record in the current schema and with the current key,
+    /// all or nothing. The tokens and failures are kept, sealed and indexed
+    /// with the current key like the users.
+    fn rewrite(db: &mut Connection, cipher: &Cipher, version: u32) -> Result<(), Box<dyn Error>> {
+        let transaction = db.transaction()?;
+        let users = SqliteStore::records(&transaction, cipher, "users")?;
+        let tokens = SqliteStore::records(&transaction, cipher, "tokens")?;
+        let failures = SqliteStore::records(&transaction, cipher, "failures")?;
+
+        transaction.execute_batch(
+            "DELETE FROM users;
+            DELETE FROM tokens;
+            DELETE FROM failures;",
+        )?;
+        for record in users {
+            let user = schema::decode_record(version, &record)?;
+            transaction.execute(
+                "INSERT INTO users (email, record) VALUES (?1, ?2)",
+                params![
+                    cipher.index(&user.email)?,
+                    cipher.seal(ron::to_string(&user)?.as_bytes())?
+                ],
+            )?;
+        }
+        for record in tokens {
+            SqliteStore::write_token(&transaction, cipher, &ron::from_str(&record)?)?;
+        }
+        for record in failures {
+            SqliteStore::write_failures(&transaction, cipher, &ron::from_str(&record)?)?;
+        }
+        transaction.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        Ok(transaction.commit()?)
+    }
+
+    /// Every record of `table`, unsealed.
+    fn records(
+        db: &Connection,
+        cipher: &Cipher,
+        table: &str,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut statement = db.prepare(&format!("SELECT CAST(record AS BLOB) FROM {}", table))?;
+        let records = statement.query_map([], |row| row.get::<_, Vec<u8>>(0))?;
+
+        let mut unsealed = vec![];
+        for record in records {
+            unsealed.push(String::from_utf8(cipher.unseal(&record?)?)?);
+        }
+        Ok(unsealed)
+    }
+
+    fn write_token(
+        db: &Connection,
+        cipher: &Cipher,
+        token: &StoredToken,
+    ) -> Result<(), Box<dyn Error>> {
+        db.execute(
+            "INSERT OR REPLACE INTO tokens (email, purpose, record) VALUES (?1, ?2, ?3)",
+            params![
+                cipher.index(&token.email)?,
+                format!("{:?}", token.purpose),
+                cipher.seal(ron::to_string(token)?.as_bytes())?
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn write_failures(
+        db: &Connection,
+        cipher: &Cipher,
+        failures: &Failures,
+    ) -> Result<(), Box<dyn Error>> {
+        db.execute(
+            "INSERT OR REPLACE INTO failures (subject, record) VALUES (?1, ?2)",
+            params![
+                cipher.index(&failures.subject.to_string())?,
+                cipher.seal(ron::to_string(failures)?.as_bytes())?
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Row ids of the tokens moving the account at `email`, whose target is
+    /// sealed in the record.
+    fn targeting(
+        db: &Connection,
+        cipher: &Cipher,
+        email: &Email,
+    ) -> Result<Vec<i64>, Box<dyn Error>> {
+        let mut statement = db.prepare("SELECT rowid, CAST(record AS BLOB) FROM tokens")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+
+        let mut rowids = vec![];
+        for row in rows {
+            let (rowid, record) = row?;
+            let token: StoredToken = ron::de::from_bytes(&cipher.unseal(&record)?)?;
+            if token.target.as_ref() == Some(email) {
+                rowids.push(rowid);
+            }
+        }
+        Ok(rowids)
+    }
+
+    /// User stored under the email `index`, read in `db`.
+    fn read_user(
+        db: &Connection,
+        cipher: &Cipher,
+        index: &str,
+    ) -> Result<Option<User>, Box<dyn Error>> {
+        let record: Option<Vec<u8>> = db
+            .query_row(
+                "SELECT CAST(record AS BLOB) FROM users WHERE email = ?1",
+                params![index],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match record {
+            Some(record) => Ok(Some(ron::de::from_bytes(&cipher.unseal(&record)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<User, Box<dyn Error>> {
+        Ok(ron::de::from_bytes(&self.cipher.unseal(record)?)?)
+    }
+}
+
+impl UserStore for SqliteStore {
+    fn get(&self, email: &Email) -> Result<Option<User>, Box<dyn Error>> {
+        SqliteStore::read_user(
+            &self.db.lock().unwrap(),
+            &self.cipher,
+            &self.cipher.index(email)?,
+        )
+    }
+
+    fn insert(&self, user: &User) -> Result<(), Box<dyn Error>> {
+        let mut db = self.db.lock().unwrap();
+        let transaction = db.transaction()?;
+        let inserted = transaction.execute(
+            "INSERT OR IGNORE INTO users (email, record) VALUES (?1, ?2)",
+            params![
+                self.cipher.index(&user.email)?,
+                self.cipher.seal(ron::to_string(user)?.as_bytes())?
+            ],
+        )?;
+        if inserted == 0 {
+            return Err(UtilsError::UserAlreadyExist.into());
+        }
+        Ok(transaction.commit()?)
+    }
+
+    fn modify(
+        &self,
+        email: &Email,
+        change: &mut dyn FnMut(&mut User) -> bool,
+    ) -> Result<Option<User>, Box<dyn Error>> {
+        let mut db = self.db.lock().unwrap();
+        let transaction = db.transaction()?;
+        let index = self.cipher.index(email)?;
+        let Some(mut user) = SqliteStore::read_user(&transaction, &self.cipher, &index)? else {
+            return Ok(None);
+        };
+        if !change(&mut user) {
+            return Ok(None);
+        }
+        transaction.execute(
+            "UPDATE users SET record = ?2 WHERE email = ?1",
+            params![index, self.cipher.seal(ron::to_string(&user)?.as_bytes())?],
+        )?;
+        transaction.commit()?;
+        Ok(Some(user))
+    }
+
+    fn rekey(&self, email: &Email, new_email: &Email) -> Result<User, Box<dyn Error>> {
+        let mut db = self.db.lock().unwrap();
+        let transaction = db.transaction()?;
+        let index = self.cipher.index(email)?;
+        let Some(mut user) = SqliteStore::read_user(&transaction, &self.cipher, &index)? else {
+            return Err(UtilsError::InvalidEmail.into());
+        };
+        user.email = new_email.clone();
+        let inserted = transaction.execute(
+            "INSERT OR IGNORE INTO users (email, record) VALUES (?1, ?2)",
+            params![
+                self.cipher.index(&user.email)?,
+                self.cipher.seal(ron::to_string(&user)?.as_bytes())?
+            ],
+        )?;
+        if inserted == 0 {
+            return Err(UtilsError::UserAlreadyExist.into());
+        }
+        transaction.execute("DELETE FROM users WHERE email = ?1", params![index])?;
+        transaction.execute("DELETE FROM tokens WHERE email = ?1", params![index])?;
+        transaction.commit()?;
+        Ok(user)
+    }
+
+    fn delete(&self, email: &Email) -> Result<bool, Box<dyn Error>> {
+        let mut db = self.db.lock().unwrap();
+        let transaction = db.transaction()?;
+        let index = self.cipher.index(email)?;
+        transaction.execute("DELETE FROM tokens WHERE email = ?1", params![index])?;
+        for rowid in SqliteStore::targeting(&transaction, &self.cipher, email)? {
+            transaction.execute("DELETE FROM tokens WHERE rowid = ?1", params![rowid])?;
+        }
+        transaction.execute(
+            "DELETE FROM failures WHERE subject = ?1",
+            params![self
+                .cipher
+                .index(&Subject::Account(email.clone()).to_string())?],
+        )?;
+        let deleted = transaction.execute("DELETE FROM users WHERE email = ?1", params![index])?;
+        transaction.commit()?;
+        Ok(deleted > 0)
+    }
+
+    fn list(&self) -> Result<Vec<User>, Box<dyn Error>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare("SELECT CAST(record AS BLOB) FROM users")?;
+        let records = statement.query_map([], |row| row.get::<_, Vec<u8>>(0))?;
+
+        let mut users = vec![];
+        for record in records {
+            users.push(self.decode(&record?)?);
+        }
+        Ok(users)
+    }
+
+    fn get_token(
+        &self,
+        email: &Email,
+        purpose: Purpose,
+    ) -> Result<Option<StoredToken>, Box<dyn Error>> {
+        let record: Option<Vec<u8>> = self
+            .db
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT CAST(record AS BLOB) FROM tokens WHERE email = ?1 AND purpose = ?2",
+                params![self.cipher.index(email)?, format!("{:?}", purpose)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match record {
+            Some(record) => Ok(Some(ron::de::from_bytes(&self.cipher.unseal(&record)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_token(&self, token: &StoredToken) -> Result<(), Box<dyn Error>> {
+        SqliteStore::write_token(&self.db.lock().unwrap(), &self.cipher, token)
+    }
+
+    fn get_failures(&self, subject: &Subject) -> Result<Option<Failures>, Box<dyn Error>> {
+        let record: Option<Vec<u8>> = self
+            .db
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT CAST(record AS BLOB) FROM failures WHERE subject = ?1",
+                params![self.cipher.index(&subject.to_string())?],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match record {
+            Some(record) => Ok(Some(ron::de::from_bytes(&self.cipher.unseal(&record)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_failures(&self, failures: &Failures) -> Result<(), Box<dyn Error>> {
+        SqliteStore::write_failures(&self.db.lock().unwrap(), &self.cipher, failures)
+    }
+
+    fn clear_failures(&self, subject: &Subject) -> Result<bool, Box<dyn Error>> {
+        let cleared = self.db.lock().unwrap().execute(
+            "DELETE FROM failures WHERE subject = ?1",
+            params![self.cipher.index(&subject.to_string())?],
+        )?;
+        Ok(cleared > 0)
+    }
+
+    fn prune_failures(&self, expired: &dyn Fn(&Failures) -> bool) -> Result<usize, Box<dyn Error>> {
+        let mut db = self.db.lock().unwrap();
+        let transaction = db.transaction()?;
+        let mut pruned = 0;
+        for record in SqliteStore::records(&transaction, &self.cipher, "failures")? {
+            let failures: Failures = ron::from_str(&record)?;
+            if expired(&failures) {
+                pruned += transaction.execute(
+                    "DELETE FROM failures WHERE subject = ?1",
+                    params![self.cipher.index(&failures.subject.to_string())?],
+                )?;
+            }
+        }
+        transaction.commit()?;
+        Ok(pruned)
+    }
+}
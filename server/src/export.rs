@@ -0,0 +1,76 @@
+use crate::database::{Failures, Purpose, Subject, UserStore};
+use serde::Serialize;
+use std::error::Error;
+use utils::{KeyCredential, User};
+use validation::Email;
+
+/// Everything stored about a user, given to them on request.
+///
+/// The password verifier, the TOTP secret and the hashes of the tokens and
+/// recovery codes are left out, they would only help to impersonate the
+/// user.
+#[derive(Serialize)]
+pub struct Export {
+    pub email: Email,
+    pub verified: bool,
+    pub two_f_a: bool,
+    pub yubikeys: Vec<KeyCredential>,
+    pub totp: bool,
+    pub recovery_codes: usize,
+    pub records: Records,
+}
+
+/// Records kept about the account besides the user. No log of past events is
+/// kept, only the current state of its tokens and failures.
+#[derive(Serialize)]
+pub struct Records {
+    /// Last token mailed for each purpose, used or not.
+    pub tokens: Vec<Token>,
+    /// Failed logins since the last success.
+    pub failures: Option<Failures>,
+}
+
+/// Stored token without its hash. Times are in seconds since the Unix epoch.
+#[derive(Serialize)]
+pub struct Token {
+    pub purpose: Purpose,
+    pub expires: u64,
+    pub attempts: u32,
+    pub used: bool,
+    pub target: Option<Email>,
+}
+
+impl Export {
+    pub fn collect(store: &dyn UserStore, user: &User) -> Result<Export, Box<dyn Error>> {
+        let mut tokens = vec![];
+        for purpose in Purpose::ALL {
+            if let Some(stored) = store.get_token(&user.email, purpose)? {
+                tokens.push(Token {
+                    purpose,
+                    expires: stored.expires,
+                    attempts: stored.attempts,
+                    used: stored.used,
+                    target: stored.target,
+                });
+            }
+        }
+
+        Ok(Export {
+            email: user.email.clone(),
+            verified: user.verified,
+            two_f_a: user.two_f_a,
+            yubikeys: user.yubikeys.clone(),
+            totp: user.totp.is_some(),
+            recovery_codes: user.recovery_codes.len(),
+            records: Records {
+                tokens,
+                failures: store.get_failures(&Subject::Account(user.email.clone()))?,
+            },
+        })
+    }
+
+    /// The export as an indented JSON document.
+    pub fn to_json(&self) -> Result<String, Box<dyn Error>> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
@@ -0,0 +1,37 @@
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Reads the secret key at `path`, warning if other users can access it.
+pub fn read(path: &Path) -> Result<String, Box<dyn Error>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            log::warn!(
+                "Key {} is accessible by other users (mode {:o}), restrict it to 600",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+    Ok(fs::read_to_string(path)?)
+}
+
+/// Saves a new secret key at `path`, which must not exist yet, readable by
+/// the owner only.
+pub fn create(path: &Path, contents: &str) -> Result<(), Box<dyn Error>> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents.as_bytes())?;
+    Ok(())
+}
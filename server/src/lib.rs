@@ -0,0 +1,15 @@
+mod action;
+mod authentication;
+pub mod config;
+mod connection;
+mod context;
+pub mod database;
+mod export;
+mod key_file;
+pub mod mailer;
+mod recovery;
+pub mod server;
+mod session;
+mod throttle;
+mod tokens;
+mod totp;
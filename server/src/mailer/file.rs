@@ -0,0 +1,69 @@
+use super::{compose, Mail, MailTransport};
+use lettre::message::Mailbox;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Writes each email to its own `<timestamp>-<uuid>.eml` file in a directory,
+/// for development.
+pub struct FileTransport {
+    directory: PathBuf,
+    from: Mailbox,
+}
+
+impl FileTransport {
+    /// Creates the directory if missing.
+    pub fn new(directory: &Path, from: Mailbox) -> Result<FileTransport, Box<dyn Error>> {
+        fs::create_dir_all(directory)?;
+        Ok(FileTransport {
+            directory: directory.to_path_buf(),
+            from,
+        })
+    }
+}
+
+impl MailTransport for FileTransport {
+    fn send_mail(&self, mail: &Mail) -> Result<(), Box<dyn Error>> {
+        let email = compose(&self.from, mail)?;
+        let name = format!("{}-{}.eml", timestamp()?, Uuid::new_v4().simple());
+
+        Ok(fs::write(self.directory.join(name), email.formatted())?)
+    }
+}
+
+/// Delivers the emails to a maildir, which mail clients can read directly.
+pub struct MaildirTransport {
+    directory: PathBuf,
+    from: Mailbox,
+}
+
+impl MaildirTransport {
+    /// Creates the `tmp`, `new` and `cur` subdirectories if missing.
+    pub fn new(directory: &Path, from: Mailbox) -> Result<MaildirTransport, Box<dyn Error>> {
+        for subdirectory in ["tmp", "new", "cur"] {
+            fs::create_dir_all(directory.join(subdirectory))?;
+        }
+        Ok(MaildirTransport {
+            directory: directory.to_path_buf(),
+            from,
+        })
+    }
+}
+
+impl MailTransport for MaildirTransport {
+    /// Written to `tmp` then moved to `new`, so readers never see a partial email.
+    fn send_mail(&self, mail: &Mail) -> Result<(), Box<dyn Error>> {
+        let email = compose(&self.from, mail)?;
+        let name = format!("{}.{}.localhost", timestamp()?, Uuid::new_v4().simple());
+
+        let tmp = self.directory.join("tmp").join(&name);
+        fs::write(&tmp, email.formatted())?;
+        Ok(fs::rename(tmp, self.directory.join("new").join(name))?)
+    }
+}
+
+fn timestamp() -> Result<u64, Box<dyn Error>> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
@@ -0,0 +1,38 @@
+use super::{Mail, MailTransport};
+use std::error::Error;
+use std::sync::Mutex;
+
+/// Keeps the emails in memory instead of sending them, for tests.
+#[derive(Default)]
+pub struct MemoryTransport {
+    mails: Mutex<Vec<Mail>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+
+    /// Emails sent so far, oldest first.
+    pub fn mails(&self) -> Vec<Mail> {
+        self.mails.lock().unwrap().clone()
+    }
+
+    /// Last email sent to `to`.
+    pub fn last_mail(&self, to: &str) -> Option<Mail> {
+        self.mails
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|mail| mail.to == to)
+            .cloned()
+    }
+}
+
+impl MailTransport for MemoryTransport {
+    fn send_mail(&self, mail: &Mail) -> Result<(), Box<dyn Error>> {
+        self.mails.lock().unwrap().push(mail.clone());
+        Ok(())
+    }
+}
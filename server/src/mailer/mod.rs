@@ -0,0 +1,90 @@
+mod file;
+mod memory;
+mod smtp;
+mod stdout;
+mod templates;
+
+use crate::config::{Delivery, Mail as MailConfig};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::Message;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+pub use file::{FileTransport, MaildirTransport};
+pub use memory::MemoryTransport;
+pub use smtp::Smtp;
+pub use stdout::StdoutTransport;
+pub use templates::{Notification, Templates};
+
+/// Email with a plain text and an HTML version of the same message.
+#[derive(Clone, Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Delivers the emails sent to the users.
+pub trait MailTransport: Send + Sync {
+    fn send_mail(&self, mail: &Mail) -> Result<(), Box<dyn Error>>;
+}
+
+/// Opens the mail transport selected in the configuration.
+pub fn open(mail: &MailConfig) -> Result<Arc<dyn MailTransport>, Box<dyn Error>> {
+    let from: Mailbox = mail.from.parse()?;
+    Ok(match &mail.delivery {
+        Delivery::Smtp { env } => Arc::new(Smtp::new(env, from)?),
+        Delivery::File { directory } => Arc::new(FileTransport::new(directory, from)?),
+        Delivery::Maildir { directory } => Arc::new(MaildirTransport::new(directory, from)?),
+        Delivery::Stdout => Arc::new(StdoutTransport::new(from)),
+    })
+}
+
+/// Renders the notifications and sends them through a transport.
+pub struct Mailer {
+    transport: Arc<dyn MailTransport>,
+    templates: Templates,
+}
+
+impl Mailer {
+    pub fn new(transport: Arc<dyn MailTransport>, templates: Templates) -> Mailer {
+        Mailer {
+            transport,
+            templates,
+        }
+    }
+
+    /// Sends `notification` to `to`, about a request made from `ip`.
+    pub fn send(
+        &self,
+        to: &str,
+        ip: IpAddr,
+        notification: &Notification,
+    ) -> Result<(), Box<dyn Error>> {
+        let mail = self.templates.render(to, ip, notification)?;
+        self.transport.send_mail(&mail)
+    }
+
+    /// Sends a notification the request does not depend on, only logging a
+    /// failure.
+    pub fn notify(&self, to: &str, ip: IpAddr, notification: &Notification) {
+        if let Err(e) = self.send(to, ip, notification) {
+            log::error!("Could not notify {}: {}", to, e);
+        }
+    }
+}
+
+/// Builds a multipart email.
+fn compose(from: &Mailbox, mail: &Mail) -> Result<Message, Box<dyn Error>> {
+    Ok(Message::builder()
+        .from(from.clone())
+        .reply_to(from.clone())
+        .to(mail.to.parse()?)
+        .subject(&mail.subject)
+        .multipart(MultiPart::alternative_plain_html(
+            mail.text.clone(),
+            mail.html.clone(),
+        ))?)
+}
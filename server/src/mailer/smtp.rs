@@ -0,0 +1,61 @@
+extern crate envfile;
+
+use super::{compose, Mail, MailTransport};
+use envfile::EnvFile;
+use lettre::message::Mailbox;
+use lettre::{transport::smtp::authentication::Credentials, SmtpTransport, Transport};
+use std::error::Error;
+use std::path::Path;
+
+/// Sends through an SMTP relay described in an env file with the following
+/// properties, `from` overriding the configured sender.
+///
+/// You must have an application password already set up from the email provider
+/// of your choice.
+///
+/// ```text
+/// username=your@email.address
+/// password=application password
+/// relay=relay.address
+/// from=from@email.address
+/// ```
+pub struct Smtp {
+    mailer: SmtpTransport,
+    from: Mailbox,
+}
+
+impl Smtp {
+    pub fn new(env: &Path, from: Mailbox) -> Result<Smtp, Box<dyn Error>> {
+        let envfile = EnvFile::new(env)?;
+        let setting = |key: &str| {
+            envfile
+                .get(key)
+                .ok_or_else(|| format!("Missing {} in {}", key, env.display()))
+        };
+
+        let creds = Credentials::new(
+            setting("username")?.to_string(),
+            setting("password")?.to_string(),
+        );
+        let mailer = SmtpTransport::relay(setting("relay")?)?
+            .credentials(creds)
+            .build();
+        let from = match envfile.get("from") {
+            Some(from) => from.parse()?,
+            None => from,
+        };
+
+        Ok(Smtp { mailer, from })
+    }
+}
+
+impl MailTransport for Smtp {
+    fn send_mail(&self, mail: &Mail) -> Result<(), Box<dyn Error>> {
+        let email = compose(&self.from, mail)?;
+
+        match self.mailer.send(&email) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
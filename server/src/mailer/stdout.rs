@@ -0,0 +1,26 @@
+use super::{compose, Mail, MailTransport};
+use lettre::message::Mailbox;
+use std::error::Error;
+use std::io::Write;
+
+/// Prints the emails on the standard output, for development.
+pub struct StdoutTransport {
+    from: Mailbox,
+}
+
+impl StdoutTransport {
+    pub fn new(from: Mailbox) -> StdoutTransport {
+        StdoutTransport { from }
+    }
+}
+
+impl MailTransport for StdoutTransport {
+    fn send_mail(&self, mail: &Mail) -> Result<(), Box<dyn Error>> {
+        let email = compose(&self.from, mail)?;
+
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&email.formatted())?;
+        stdout.write_all(b"\n\n")?;
+        Ok(stdout.flush()?)
+    }
+}
@@ -0,0 +1,240 @@
+use super::Mail;
+use handlebars::Handlebars;
+use serde::Serialize;
+use std::error::Error;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::SystemTime;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+use utils::SecondFactor;
+
+/// Built-in templates, by file name.
+macro_rules! builtin {
+    ($($name:literal),*) => {
+        [$(($name, include_str!(concat!("../../templates/", $name, ".hbs")))),*]
+    };
+}
+
+const BUILTIN: [(&str, &str); 34] = builtin![
+    "layout.html",
+    "reset.subject",
+    "reset.txt",
+    "reset.html",
+    "verify.subject",
+    "verify.txt",
+    "verify.html",
+    "already_registered.subject",
+    "already_registered.txt",
+    "already_registered.html",
+    "two_f_a_changed.subject",
+    "two_f_a_changed.txt",
+    "two_f_a_changed.html",
+    "factor_added.subject",
+    "factor_added.txt",
+    "factor_added.html",
+    "factor_removed.subject",
+    "factor_removed.txt",
+    "factor_removed.html",
+    "password_changed.subject",
+    "password_changed.txt",
+    "password_changed.html",
+    "email_change.subject",
+    "email_change.txt",
+    "email_change.html",
+    "email_changed.subject",
+    "email_changed.txt",
+    "email_changed.html",
+    "account_deleted.subject",
+    "account_deleted.txt",
+    "account_deleted.html",
+    "new_login.subject",
+    "new_login.txt",
+    "new_login.html"
+];
+
+/// Emails sent to the users, rendered from the template of the same name.
+pub enum Notification {
+    /// Password reset token, valid until `expires`.
+    Reset {
+        token: String,
+        expires: SystemTime,
+    },
+    /// Email verification token of a new account, valid until `expires`.
+    Verify {
+        token: String,
+        expires: SystemTime,
+    },
+    /// Registration attempted with the email of an existing account.
+    AlreadyRegistered,
+    TwoFAChanged {
+        two_f_a: bool,
+    },
+    /// Second factor added, with the label of the YubiKey if it is one.
+    FactorAdded {
+        factor: SecondFactor,
+        label: Option<String>,
+    },
+    FactorRemoved {
+        factor: SecondFactor,
+        label: Option<String>,
+    },
+    /// Token confirming the new email of an account, valid until `expires`.
+    EmailChange {
+        token: String,
+        expires: SystemTime,
+    },
+    /// Account moved to `email`, with the token moving it back until
+    /// `expires`.
+    EmailChanged {
+        email: String,
+        token: String,
+        expires: SystemTime,
+    },
+    /// Password changed by the logged in user, the other sessions ended.
+    PasswordChanged,
+    AccountDeleted,
+    NewLogin,
+}
+
+impl Notification {
+    fn name(&self) -> &'static str {
+        match self {
+            Notification::Reset { .. } => "reset",
+            Notification::Verify { .. } => "verify",
+            Notification::AlreadyRegistered => "already_registered",
+            Notification::TwoFAChanged { .. } => "two_f_a_changed",
+            Notification::FactorAdded { .. } => "factor_added",
+            Notification::FactorRemoved { .. } => "factor_removed",
+            Notification::PasswordChanged => "password_changed",
+            Notification::EmailChange { .. } => "email_change",
+            Notification::EmailChanged { .. } => "email_changed",
+            Notification::AccountDeleted => "account_deleted",
+            Notification::NewLogin => "new_login",
+        }
+    }
+}
+
+/// Values available to the templates, `null` when they do not apply.
+#[derive(Serialize)]
+struct Variables<'a> {
+    brand: &'a str,
+    email: &'a str,
+    timestamp: String,
+    ip: String,
+    token: Option<&'a str>,
+    expires: Option<String>,
+    two_f_a: Option<bool>,
+    factor: Option<String>,
+    label: Option<&'a str>,
+    new_email: Option<&'a str>,
+}
+
+/// Handlebars templates rendering the notifications as multipart emails.
+///
+/// Each notification has a `<name>.subject.hbs`, `<name>.txt.hbs` and
+/// `<name>.html.hbs` template, the HTML ones being wrapped in the
+/// `layout.html.hbs` partial. Only the HTML is escaped.
+pub struct Templates {
+    brand: String,
+    text: Handlebars<'static>,
+    html: Handlebars<'static>,
+}
+
+impl Templates {
+    /// Loads the built-in templates, replaced by the files of the same name
+    /// found in `directory`.
+    pub fn new(brand: &str, directory: Option<&Path>) -> Result<Templates, Box<dyn Error>> {
+        let mut text = Handlebars::new();
+        text.register_escape_fn(handlebars::no_escape);
+        text.set_strict_mode(true);
+        let mut html = Handlebars::new();
+        html.set_strict_mode(true);
+
+        for (name, builtin) in BUILTIN {
+            let file = directory.map(|directory| directory.join(format!("{}.hbs", name)));
+            let source = match file {
+                Some(file) if file.exists() => {
+                    log::info!("Using template {}", file.display());
+                    std::fs::read_to_string(file)?
+                }
+                _ => builtin.to_string(),
+            };
+
+            match name.strip_suffix(".html") {
+                Some("layout") => html.register_partial("layout", source)?,
+                Some(_) => html.register_template_string(name, source)?,
+                None => text.register_template_string(name, source)?,
+            }
+        }
+
+        Ok(Templates {
+            brand: brand.to_string(),
+            text,
+            html,
+        })
+    }
+
+    pub fn render(
+        &self,
+        to: &str,
+        ip: IpAddr,
+        notification: &Notification,
+    ) -> Result<Mail, Box<dyn Error>> {
+        let mut variables = Variables {
+            brand: &self.brand,
+            email: to,
+            timestamp: format_time(SystemTime::now())?,
+            ip: ip.to_string(),
+            token: None,
+            expires: None,
+            two_f_a: None,
+            factor: None,
+            label: None,
+            new_email: None,
+        };
+        match notification {
+            Notification::Reset { token, expires }
+            | Notification::Verify { token, expires }
+            | Notification::EmailChange { token, expires } => {
+                variables.token = Some(token);
+                variables.expires = Some(format_time(*expires)?);
+            }
+            Notification::EmailChanged {
+                email,
+                token,
+                expires,
+            } => {
+                variables.new_email = Some(email);
+                variables.token = Some(token);
+                variables.expires = Some(format_time(*expires)?);
+            }
+            Notification::TwoFAChanged { two_f_a } => variables.two_f_a = Some(*two_f_a),
+            Notification::FactorAdded { factor, label }
+            | Notification::FactorRemoved { factor, label } => {
+                variables.factor = Some(factor.to_string());
+                variables.label = label.as_deref();
+            }
+            Notification::AlreadyRegistered
+            | Notification::PasswordChanged
+            | Notification::AccountDeleted
+            | Notification::NewLogin => {}
+        }
+
+        let name = notification.name();
+        Ok(Mail {
+            to: to.to_string(),
+            subject: self
+                .text
+                .render(&format!("{}.subject", name), &variables)?
+                .trim()
+                .to_string(),
+            text: self.text.render(&format!("{}.txt", name), &variables)?,
+            html: self.html.render(&format!("{}.html", name), &variables)?,
+        })
+    }
+}
+
+fn format_time(time: SystemTime) -> Result<String, Box<dyn Error>> {
+    Ok(OffsetDateTime::from(time).format(&Rfc2822)?)
+}
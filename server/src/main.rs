@@ -1,55 +1,141 @@
-mod action;
-mod authentication;
-mod connection;
-mod database;
-mod mailer;
-
-#[macro_use]
-extern crate lazy_static;
-
-use crate::action::Action;
-use crate::authentication::Authenticate;
-use crate::connection::Connection;
+use server::config::Config;
+use server::database::{self, Subject, SCHEMA_VERSION};
+use server::mailer;
+use server::server::Server;
 use simple_logger::SimpleLogger;
-use std::net::TcpListener;
-use std::thread;
-
-fn handle_client(mut connection: Connection) {
-    loop {
-        match Authenticate::perform(&mut connection) {
-            Ok(Some(mut user)) => {
-                while let Ok(true) = Action::perform(&mut user, &mut connection) {}
+use std::path::PathBuf;
+
+const CONFIG_PATH: &str = "server.ron";
+
+/// What the server was started for, selected with a command line flag.
+enum Mode {
+    Serve,
+    /// `--migrate`: upgrades the stored users to the current schema.
+    Migrate,
+    /// `--check`: reports whether the stored users need a migration.
+    Check,
+    /// `--unlock <email|address>`: forgets the authentication failures of an
+    /// account or a client address. With the RON storage, the server must be
+    /// stopped as it would overwrite the change.
+    Unlock(Subject),
+}
+
+fn main() {
+    SimpleLogger::new()
+        .with_module_level("rustls", log::LevelFilter::Warn)
+        .env()
+        .init()
+        .unwrap();
+
+    log::info!("Staring server");
+
+    let mut mode = Mode::Serve;
+    let mut config_path = PathBuf::from(CONFIG_PATH);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--migrate" => mode = Mode::Migrate,
+            "--check" => mode = Mode::Check,
+            "--unlock" => mode = Mode::Unlock(subject(args.next())),
+            _ => config_path = PathBuf::from(arg),
+        }
+    }
+    let config = Config::load(&config_path).unwrap();
+
+    match mode {
+        Mode::Serve => {}
+        Mode::Migrate => {
+            database::open(&config.storage, config.encryption.as_ref()).unwrap();
+            log::info!("Storage is at schema version {}", SCHEMA_VERSION);
+            return;
+        }
+        Mode::Check => check(&config),
+        Mode::Unlock(subject) => {
+            let store = database::open(&config.storage, config.encryption.as_ref()).unwrap();
+            if store.clear_failures(&subject).unwrap() {
+                log::info!("Unlocked {}", subject);
+            } else {
+                log::warn!("No failures recorded for {}", subject);
             }
-            Err(_) => return,
-            _ => {}
+            return;
         }
     }
+
+    Server::runtime(&config).unwrap().block_on(serve(config));
 }
 
-const SERVER_IP: &str = "127.0.0.1:8080";
+async fn serve(config: Config) {
+    let mailer = mailer::open(&config.mail).unwrap();
+    let server = Server::bind(config, mailer).await.unwrap();
 
-fn main() {
-    SimpleLogger::new().env().init().unwrap();
+    log::info!("Server is UP.");
+    log::info!("Serving clients on {}", server.local_addr().unwrap());
 
-    log::info!("Staring server");
+    server.serve(shutdown_signal()).await.unwrap();
 
-    let listener = TcpListener::bind(SERVER_IP).unwrap();
+    log::info!("Server DOWN.");
+}
 
-    log::info!("Server is UP.");
-    log::info!("Serving clients on {}", SERVER_IP);
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                thread::spawn(move || {
-                    handle_client(Connection::new(stream));
-                });
-            }
-            Err(e) => {
-                println!("Connection failed with error: {}", e);
-            }
+/// Parses the argument of `--unlock`, exiting if it is missing or invalid.
+fn subject(arg: Option<String>) -> Subject {
+    let arg = arg.unwrap_or_default();
+    if let Ok(address) = arg.parse() {
+        return Subject::Address(address);
+    }
+    match arg.parse() {
+        Ok(email) => Subject::Account(email),
+        Err(_) => {
+            log::error!("--unlock expects an email or an IP address, got {:?}", arg);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Exits with 0 if the storage is up to date, 1 if it needs a migration or
+/// to be sealed with the current key, and 2 if it was written by a newer
+/// version.
+fn check(config: &Config) -> ! {
+    let status = database::status(&config.storage, config.encryption.as_ref()).unwrap();
+    match status {
+        Some(status) if status.version > SCHEMA_VERSION => {
+            log::error!(
+                "Storage is at schema version {}, newer than the supported version {}",
+                status.version,
+                SCHEMA_VERSION
+            );
+            std::process::exit(2);
+        }
+        Some(status) if status.version < SCHEMA_VERSION => {
+            log::warn!(
+                "Storage is at schema version {}, run with --migrate to upgrade it to {}",
+                status.version,
+                SCHEMA_VERSION
+            );
+            std::process::exit(1);
+        }
+        Some(status) if !status.sealed => {
+            log::warn!("Storage is not sealed with the current key, run with --migrate");
+            std::process::exit(1);
+        }
+        _ => {
+            log::info!("Storage is up to date");
+            std::process::exit(0);
         }
     }
+}
+
+/// Completes on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-    println!("Server DOWN.");
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap();
 }
@@ -0,0 +1,52 @@
+use crate::database::UserStore;
+use std::error::Error;
+use std::sync::Arc;
+use utils::crypto::{generate_recovery_code, hash_sha256};
+use utils::User;
+use validation::Email;
+
+/// Single-use codes standing in for a lost second factor.
+///
+/// The users get them in clear once, only their hash is stored. A code is
+/// found and removed in one change of the user, so it cannot be used twice
+/// nor brought back by another change.
+pub struct RecoveryCodes {
+    store: Arc<dyn UserStore>,
+    count: usize,
+}
+
+impl RecoveryCodes {
+    pub fn new(store: Arc<dyn UserStore>, count: usize) -> RecoveryCodes {
+        RecoveryCodes { store, count }
+    }
+
+    /// New codes to give to the user, with the hashes to store.
+    pub fn generate(&self) -> (Vec<String>, Vec<Vec<u8>>) {
+        let codes: Vec<_> = (0..self.count).map(|_| generate_recovery_code()).collect();
+        let hashes = codes.iter().map(|code| hash(code)).collect();
+        (codes, hashes)
+    }
+
+    /// Whether `code` is one of the codes of `email`, which then cannot be
+    /// used again. Returns the user updated if so.
+    pub fn redeem(&self, email: &Email, code: &str) -> Result<Option<User>, Box<dyn Error>> {
+        let hash = hash(code);
+        self.store.modify(email, &mut |user| {
+            let Some(index) = user
+                .recovery_codes
+                .iter()
+                .position(|stored| *stored == hash)
+            else {
+                log::error!("Wrong or already used recovery code");
+                return false;
+            };
+            user.recovery_codes.remove(index);
+            log::info!("{} recovery codes left", user.recovery_codes.len());
+            true
+        })
+    }
+}
+
+fn hash(code: &str) -> Vec<u8> {
+    hash_sha256(code.to_lowercase().as_bytes())
+}
@@ -0,0 +1,162 @@
+use crate::config::Config;
+use crate::connection::{Acceptor, Connection};
+use crate::context::Context;
+use crate::database;
+use crate::mailer::{MailTransport, Mailer, Templates};
+use crate::recovery::RecoveryCodes;
+use crate::session::Session;
+use crate::throttle::Throttle;
+use crate::tokens::Tokens;
+use crate::totp::Totp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
+use utils::Request;
+
+/// Accepts clients on the configured address and serves each of them on a
+/// blocking thread, at most `max_sessions` at a time.
+///
+/// The sessions stay blocking on purpose: the TLS and Noise streams, the
+/// stores and the mail transports all do blocking I/O, which would stall the
+/// async workers. Only accepting the clients and the shutdown are async.
+pub struct Server {
+    listener: TcpListener,
+    acceptor: Acceptor,
+    context: Context,
+    sessions: Arc<Semaphore>,
+    /// Sockets of the sessions in progress, closed if they outlast the
+    /// shutdown.
+    sockets: Arc<Mutex<HashMap<SocketAddr, TcpStream>>>,
+}
+
+impl Server {
+    /// Binds the configured address, emails are sent through `mailer`.
+    pub async fn bind(
+        config: Config,
+        mailer: Arc<dyn MailTransport>,
+    ) -> Result<Server, Box<dyn Error>> {
+        config.validate()?;
+        let store = database::open(&config.storage, config.encryption.as_ref())?;
+        log::info!(
+            "{} users in {:?} storage",
+            store.list()?.len(),
+            config.storage
+        );
+
+        let templates = Templates::new(&config.mail.brand, config.mail.templates.as_deref())?;
+
+        Ok(Server {
+            listener: TcpListener::bind(&config.address).await?,
+            acceptor: Acceptor::new(&config.transport)?,
+            sessions: Arc::new(Semaphore::new(config.max_sessions)),
+            sockets: Arc::new(Mutex::new(HashMap::new())),
+            context: Context {
+                tokens: Arc::new(Tokens::new(store.clone(), config.tokens.clone())),
+                throttle: Arc::new(Throttle::new(store.clone(), config.throttling.clone())),
+                totp: Arc::new(Totp::new(store.clone(), config.totp_skew)),
+                recovery: Arc::new(RecoveryCodes::new(store.clone(), config.recovery_codes)),
+                store,
+                mailer: Arc::new(Mailer::new(mailer, templates)),
+                config: Arc::new(config),
+            },
+        })
+    }
+
+    /// Runtime to serve with, whose blocking pool holds `max_sessions`
+    /// threads, one per session.
+    pub fn runtime(config: &Config) -> Result<Runtime, Box<dyn Error>> {
+        Ok(tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .max_blocking_threads(config.max_sessions)
+            .build()?)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Box<dyn Error>> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves clients until `shutdown` completes, then waits for the sessions
+    /// in progress to end, closing those still open after the shutdown
+    /// timeout.
+    pub async fn serve(self, shutdown: impl Future<Output = ()>) -> Result<(), Box<dyn Error>> {
+        tokio::pin!(shutdown);
+
+        loop {
+            let permit = tokio::select! {
+                permit = self.sessions.clone().acquire_owned() => permit?,
+                _ = &mut shutdown => break,
+            };
+            let (stream, peer) = tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::error!("Connection failed with error: {}", e);
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+            let stream = stream.into_std()?;
+            stream.set_nonblocking(false)?;
+            self.sockets
+                .lock()
+                .unwrap()
+                .insert(peer, stream.try_clone()?);
+
+            let acceptor = self.acceptor.clone();
+            let context = self.context.clone();
+            let sockets = self.sockets.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = Server::handle_client(stream, peer, &acceptor, context) {
+                    log::error!("Client disconnected: {}", e);
+                }
+                sockets.lock().unwrap().remove(&peer);
+                drop(permit);
+            });
+        }
+
+        let max_sessions = self.context.config.max_sessions as u32;
+        let in_flight = max_sessions as usize - self.sessions.available_permits();
+        log::info!("Shutting down, waiting for {} sessions", in_flight);
+        let ended = self.sessions.acquire_many(max_sessions);
+        tokio::pin!(ended);
+        let timeout = self.context.config.timeouts.shutdown();
+        if let Ok(ended) = tokio::time::timeout(timeout, &mut ended).await {
+            let _sessions = ended?;
+            return Ok(());
+        }
+
+        {
+            let sockets = self.sockets.lock().unwrap();
+            log::warn!("Dropping {} sessions still in progress", sockets.len());
+            for socket in sockets.values() {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+        }
+        let _sessions = ended.await?;
+        Ok(())
+    }
+
+    fn handle_client(
+        stream: TcpStream,
+        peer: SocketAddr,
+        acceptor: &Acceptor,
+        context: Context,
+    ) -> Result<(), Box<dyn Error>> {
+        log::info!("Serving {}", peer);
+
+        let mut connection = Connection::new(stream, acceptor, &context.config.timeouts)?;
+        let mut session = Session::new(context, peer.ip());
+        loop {
+            match connection.receive()? {
+                Request::Exit => return Ok(()),
+                request => connection.send(&session.handle(request))?,
+            }
+        }
+    }
+}
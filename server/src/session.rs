@@ -0,0 +1,330 @@
+use crate::{action::Action, authentication::Authenticate, context::Context};
+use std::error::Error;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use utils::{Error as UtilsError, Request, Response, User};
+
+/// Progress of a client through the protocol.
+///
+/// Each state only accepts the requests that may follow it; anything else is
+/// answered with `UnexpectedRequest` and leaves the state untouched.
+#[derive(Clone, Debug)]
+pub enum State {
+    Unauthenticated,
+    /// Account data received, waiting for the second factor to enroll.
+    Registering {
+        user: User,
+    },
+    /// Password proven for an account whose email is not confirmed yet,
+    /// waiting for the mailed verification token.
+    EmailUnverified {
+        user: User,
+    },
+    /// SRP challenge sent, waiting for the client proof. `valid` is false
+    /// when `user` is a dummy standing in for an unknown email.
+    PasswordPending {
+        user: User,
+        valid: bool,
+        b: [u8; 32],
+        a_pub: Vec<u8>,
+        challenge: [u8; 16],
+    },
+    /// Password proven, waiting for the second factor: the YubiKey signature of
+    /// `challenge`, a TOTP code or a recovery code.
+    PasswordVerified {
+        user: User,
+        challenge: [u8; 16],
+    },
+    /// Reset requested, waiting for the second factor as after the password.
+    /// For an unknown email, `user` is a dummy whose key no one can sign with.
+    ResetPending {
+        user: User,
+        challenge: [u8; 16],
+    },
+    /// Reset token checked, waiting for the new password verifier.
+    TokenVerified {
+        user: User,
+    },
+    Authenticated {
+        user: User,
+    },
+    /// Logged in and changing the password, waiting for the proof of the
+    /// current one. Any other request abandons the change.
+    PasswordChangePending {
+        user: User,
+        b: [u8; 32],
+        a_pub: Vec<u8>,
+    },
+}
+
+impl State {
+    fn logged_in(&self) -> bool {
+        matches!(
+            self,
+            State::Authenticated { .. } | State::PasswordChangePending { .. }
+        )
+    }
+}
+
+/// Second factor asked again during an authenticated session, before its
+/// sensitive actions.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum StepUp {
+    #[default]
+    None,
+    /// Challenge sent, waiting for its signature, a TOTP code or a recovery
+    /// code.
+    Pending { challenge: [u8; 16] },
+    /// Second factor verified at `at`.
+    Proven { at: Instant },
+}
+
+impl StepUp {
+    /// Whether a second factor was verified less than `window` ago.
+    fn fresh(&self, window: Duration) -> bool {
+        matches!(self, StepUp::Proven { at } if at.elapsed() <= window)
+    }
+}
+
+/// Next state and response to send, or an internal server error.
+pub type Transition = Result<(State, Response), Box<dyn Error>>;
+
+pub struct Session {
+    state: State,
+    /// Reset whenever the client is not authenticated anymore.
+    step_up: StepUp,
+    context: Context,
+    /// Address of the client, shown in the emails.
+    peer: IpAddr,
+}
+
+impl Session {
+    pub fn new(context: Context, peer: IpAddr) -> Session {
+        Session {
+            state: State::Unauthenticated,
+            step_up: StepUp::None,
+            context,
+            peer,
+        }
+    }
+
+    /// Handles a request and moves to the next state.
+    ///
+    /// If the request fails on the server side, an authenticated client stays
+    /// logged in while any flow in progress is abandoned.
+    pub fn handle(&mut self, request: Request) -> Response {
+        let state = std::mem::replace(&mut self.state, State::Unauthenticated);
+        let fallback = match &state {
+            State::Authenticated { user } | State::PasswordChangePending { user, .. } => {
+                State::Authenticated { user: user.clone() }
+            }
+            _ => State::Unauthenticated,
+        };
+
+        let transition =
+            Session::transition(&self.context, self.peer, &mut self.step_up, state, request);
+        let response = match transition {
+            Ok((state, response)) => {
+                self.state = state;
+                response
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                self.state = fallback;
+                Response::Error(UtilsError::ServerError)
+            }
+        };
+        if !self.state.logged_in() {
+            self.step_up = StepUp::None;
+        }
+        response
+    }
+
+    fn transition(
+        context: &Context,
+        peer: IpAddr,
+        step_up: &mut StepUp,
+        state: State,
+        request: Request,
+    ) -> Transition {
+        let state = match state {
+            State::Authenticated { user } => match Session::current(context, user)? {
+                Some(user) => State::Authenticated { user },
+                None => {
+                    log::error!("{}", UtilsError::SessionExpired);
+                    return Ok((
+                        State::Unauthenticated,
+                        Response::Error(UtilsError::SessionExpired),
+                    ));
+                }
+            },
+            state => state,
+        };
+
+        match (state, request) {
+            // Password change
+            (State::PasswordChangePending { user, b, a_pub }, Request::ChangePassword(data)) => {
+                Action::change_password(context, peer, user, &b, &a_pub, data)
+            }
+            (State::PasswordChangePending { user, .. }, request) => Session::transition(
+                context,
+                peer,
+                step_up,
+                State::Authenticated { user },
+                request,
+            ),
+
+            // Step-up
+            (State::Authenticated { user }, Request::StartStepUp) => {
+                Authenticate::start_step_up(step_up, user)
+            }
+            (State::Authenticated { user }, Request::ProveStepUp(data)) => {
+                Authenticate::prove_step_up(context, peer, step_up, user, data)
+            }
+            (State::Authenticated { user }, Request::ProveStepUpTotp(data)) => {
+                Authenticate::prove_step_up_totp(context, peer, step_up, user, data)
+            }
+            (State::Authenticated { user }, Request::ProveStepUpRecoveryCode(data)) => {
+                Authenticate::prove_step_up_recovery_code(context, peer, step_up, user, data)
+            }
+            (State::Authenticated { user }, request)
+                if Session::sensitive(&user, &request)
+                    && !step_up.fresh(context.config.step_up_window()) =>
+            {
+                log::error!("{}", UtilsError::StepUpRequired);
+                Ok((
+                    State::Authenticated { user },
+                    Response::Error(UtilsError::StepUpRequired),
+                ))
+            }
+
+            // Actions
+            (State::Authenticated { user }, Request::Switch2FA) => {
+                Action::switch_2fa(context, peer, user)
+            }
+            (State::Authenticated { user }, Request::EnrollTotp(data)) => {
+                Action::enroll_totp(context, peer, user, data)
+            }
+            (State::Authenticated { user }, Request::RegenerateRecoveryCodes) => {
+                Action::regenerate_recovery_codes(context, peer, user)
+            }
+            (State::Authenticated { user }, Request::ListKeys) => Action::list_keys(user),
+            (State::Authenticated { user }, Request::EnrollKey(data)) => {
+                Action::enroll_key(context, peer, user, data)
+            }
+            (State::Authenticated { user }, Request::RevokeKey(data)) => {
+                Action::revoke_key(context, peer, user, data)
+            }
+            (State::Authenticated { user }, Request::StartPasswordChange(data)) => {
+                Action::start_password_change(context, peer, user, data)
+            }
+            (State::Authenticated { user }, Request::StartEmailChange(data)) => {
+                Action::start_email_change(context, peer, user, data)
+            }
+            (State::Authenticated { user }, Request::ConfirmEmailChange(data)) => {
+                Action::confirm_email_change(context, peer, user, data)
+            }
+            (State::Authenticated { user }, Request::ExportData) => {
+                Action::export_data(context, user)
+            }
+            (State::Authenticated { user }, Request::DeleteAccount) => {
+                Action::delete_account(context, peer, user)
+            }
+            (State::Authenticated { .. }, Request::Logout) => Action::logout(),
+            (state @ State::Authenticated { .. }, _) => Session::unexpected(state),
+
+            // Starting a flow abandons the one in progress
+            (_, Request::Register(data)) => Authenticate::register(data),
+            (_, Request::StartAuthentication(data)) => {
+                Authenticate::start_authentication(context, peer, data)
+            }
+            (_, Request::StartReset(data)) => Authenticate::start_reset(context, peer, data),
+            (_, Request::RedeemToken(data)) => Authenticate::redeem_token(context, data),
+            (_, Request::RevertEmail(data)) => Authenticate::revert_email(context, data),
+
+            // Register
+            (State::Registering { user }, Request::RegisterYubiKey(data)) => {
+                Authenticate::register_yubikey(context, peer, user, data)
+            }
+            (State::Registering { user }, Request::RegisterTotp(data)) => {
+                Authenticate::register_totp(context, peer, user, data)
+            }
+            (State::EmailUnverified { user }, Request::VerifyEmail(data)) => {
+                Authenticate::verify_email(context, user, data)
+            }
+            (State::EmailUnverified { user }, Request::ResendVerification) => {
+                Authenticate::resend_verification(context, peer, user)
+            }
+
+            // Authenticate
+            (
+                State::PasswordPending {
+                    user,
+                    valid,
+                    b,
+                    a_pub,
+                    challenge,
+                },
+                Request::ProvePassword(data),
+            ) => Authenticate::prove_password(
+                context, peer, user, valid, &b, &a_pub, challenge, data,
+            ),
+            (State::PasswordVerified { user, challenge }, Request::ProveYubiKey(data)) => {
+                Authenticate::prove_yubikey(context, peer, user, &challenge, data)
+            }
+            (State::PasswordVerified { user, .. }, Request::ProveTotp(data)) => {
+                Authenticate::prove_totp(context, peer, user, data)
+            }
+            (State::PasswordVerified { user, .. }, Request::ProveRecoveryCode(data)) => {
+                Authenticate::prove_recovery_code(context, peer, user, data)
+            }
+
+            // Reset password
+            (State::ResetPending { user, challenge }, Request::ProveYubiKey(data)) => {
+                Authenticate::prove_reset_yubikey(context, peer, user, &challenge, data)
+            }
+            (State::ResetPending { user, .. }, Request::ProveTotp(data)) => {
+                Authenticate::prove_reset_totp(context, peer, user, data)
+            }
+            (State::ResetPending { user, .. }, Request::ProveRecoveryCode(data)) => {
+                Authenticate::prove_reset_recovery_code(context, peer, user, data)
+            }
+            (State::TokenVerified { user }, Request::SetPassword(data)) => {
+                Authenticate::set_password(context, peer, user, data)
+            }
+
+            (state, _) => Session::unexpected(state),
+        }
+    }
+
+    /// Stored version of the logged in `user`, none if the account was
+    /// removed, moved to another email or its password changed since, which
+    /// ends the session.
+    fn current(context: &Context, user: User) -> Result<Option<User>, Box<dyn Error>> {
+        Ok(context
+            .store
+            .get(&user.email)?
+            .filter(|current| current.salt == user.salt && current.verifier == user.verifier))
+    }
+
+    /// Whether `request` weakens the account of `user` or adds a way into it,
+    /// and so needs a recent step-up.
+    fn sensitive(user: &User, request: &Request) -> bool {
+        match request {
+            Request::Switch2FA => user.two_f_a,
+            Request::RevokeKey(_)
+            | Request::EnrollKey(_)
+            | Request::EnrollTotp(_)
+            | Request::RegenerateRecoveryCodes
+            | Request::StartPasswordChange(_)
+            | Request::StartEmailChange(_)
+            | Request::DeleteAccount => true,
+            _ => false,
+        }
+    }
+
+    fn unexpected(state: State) -> Transition {
+        log::error!("{}", UtilsError::UnexpectedRequest);
+        Ok((state, Response::Error(UtilsError::UnexpectedRequest)))
+    }
+}
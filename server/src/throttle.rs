@@ -0,0 +1,132 @@
+use crate::config::{Limits, Throttling};
+use crate::database::{Failures, Subject, UserStore};
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use validation::Email;
+
+/// Counts the authentication failures per account and per client address,
+/// delaying then locking out the next attempts.
+///
+/// The counters are kept in the user storage, so a restart does not reset them.
+/// Only the accounts that exist are counted, unknown emails are throttled by
+/// their address, and the counters that no longer block anything are pruned
+/// every `forget_after` seconds.
+pub struct Throttle {
+    store: Arc<dyn UserStore>,
+    config: Throttling,
+    /// Serializes the updates, so concurrent failures are all counted. Holds
+    /// the time of the last pruning.
+    lock: Mutex<u64>,
+}
+
+impl Throttle {
+    pub fn new(store: Arc<dyn UserStore>, config: Throttling) -> Throttle {
+        Throttle {
+            store,
+            config,
+            lock: Mutex::new(0),
+        }
+    }
+
+    /// Seconds to wait before `email` can be tried from `address`, 0 if it
+    /// can be right away.
+    pub fn wait(&self, email: &Email, address: IpAddr) -> Result<u64, Box<dyn Error>> {
+        let now = now()?;
+        let mut wait = 0;
+        for subject in Throttle::subjects(email, address) {
+            if let Some(failures) = self.store.get_failures(&subject)? {
+                wait = wait.max(failures.blocked_until.saturating_sub(now));
+            }
+        }
+        Ok(wait)
+    }
+
+    pub fn failed(&self, email: &Email, address: IpAddr) -> Result<(), Box<dyn Error>> {
+        let mut pruned = self.lock.lock().unwrap();
+        let now = now()?;
+        if now.saturating_sub(*pruned) >= self.config.forget_after {
+            self.prune(now)?;
+            *pruned = now;
+        }
+
+        let mut subjects = vec![Subject::Address(address)];
+        if self.store.get(email)?.is_some() {
+            subjects.push(Subject::Account(email.clone()));
+        }
+        for subject in subjects {
+            let limits = match subject {
+                Subject::Account(_) => &self.config.account,
+                Subject::Address(_) => &self.config.address,
+            };
+            let failures = match self.store.get_failures(&subject)? {
+                Some(failures) if now.saturating_sub(failures.last) < self.config.forget_after => {
+                    failures
+                }
+                _ => Failures {
+                    subject,
+                    count: 0,
+                    last: now,
+                    blocked_until: 0,
+                },
+            };
+
+            let failures = Throttle::count(limits, failures, now);
+            if failures.count == 0 {
+                log::warn!("Locking out {}", failures.subject);
+            }
+            self.store.put_failures(&failures)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the failures of the account, those of the address are kept so
+    /// that a known account cannot be used to reset them.
+    pub fn succeeded(&self, email: &Email) -> Result<(), Box<dyn Error>> {
+        let _lock = self.lock.lock().unwrap();
+        self.store
+            .clear_failures(&Subject::Account(email.clone()))?;
+        Ok(())
+    }
+
+    fn subjects(email: &Email, address: IpAddr) -> [Subject; 2] {
+        [Subject::Account(email.clone()), Subject::Address(address)]
+    }
+
+    /// Forgets the failures that are neither blocking nor recent enough to
+    /// be counted again.
+    fn prune(&self, now: u64) -> Result<(), Box<dyn Error>> {
+        let forget_after = self.config.forget_after;
+        let pruned = self.store.prune_failures(&|failures| {
+            failures.blocked_until <= now && now.saturating_sub(failures.last) >= forget_after
+        })?;
+        if pruned > 0 {
+            log::info!("Forgot the failures of {} subjects", pruned);
+        }
+        Ok(())
+    }
+
+    /// Adds a failure, blocking the subject for the delay it now deserves.
+    /// Reaching the lockout restarts the count after the lockout.
+    fn count(limits: &Limits, mut failures: Failures, now: u64) -> Failures {
+        failures.count += 1;
+        failures.last = now;
+
+        if failures.count >= limits.lockout_after {
+            failures.count = 0;
+            failures.blocked_until = now + limits.lockout;
+        } else if failures.count > limits.free_attempts {
+            let doublings = failures.count - limits.free_attempts - 1;
+            let delay = limits
+                .base_delay
+                .saturating_mul(1u64.checked_shl(doublings).unwrap_or(u64::MAX));
+            failures.blocked_until = now.saturating_add(delay);
+        }
+        failures
+    }
+}
+
+fn now() -> Result<u64, Box<dyn Error>> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
@@ -0,0 +1,119 @@
+use crate::config::Tokens as TokensConfig;
+use crate::database::{Purpose, StoredToken, UserStore};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+use validation::Email;
+
+/// Single-use tokens mailed to the users.
+///
+/// Only their hash is stored, with an expiry and a count of failed attempts
+/// bounding how many guesses can be made against each of them.
+pub struct Tokens {
+    store: Arc<dyn UserStore>,
+    config: TokensConfig,
+    /// Serializes the redemptions, so concurrent guesses are all counted.
+    lock: Mutex<()>,
+}
+
+impl Tokens {
+    pub fn new(store: Arc<dyn UserStore>, config: TokensConfig) -> Tokens {
+        Tokens {
+            store,
+            config,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Generates a new token for `email`, replacing the previous one with the
+    /// same purpose. Returns the token and its expiry.
+    pub fn issue(
+        &self,
+        email: &Email,
+        purpose: Purpose,
+    ) -> Result<(String, SystemTime), Box<dyn Error>> {
+        self.issue_moving(email, purpose, None)
+    }
+
+    /// Generates a new token for `email` like `issue`, which moves an account
+    /// to or from `target`.
+    pub fn issue_moving(
+        &self,
+        email: &Email,
+        purpose: Purpose,
+        target: Option<&Email>,
+    ) -> Result<(String, SystemTime), Box<dyn Error>> {
+        let token = Uuid::new_v4().as_hyphenated().to_string();
+        let expires = SystemTime::now() + self.config.lifetime(purpose);
+
+        self.store.put_token(&StoredToken {
+            email: email.clone(),
+            purpose,
+            hash: hash(&token),
+            expires: expires.duration_since(UNIX_EPOCH)?.as_secs(),
+            attempts: 0,
+            used: false,
+            target: target.cloned(),
+        })?;
+        Ok((token, expires))
+    }
+
+    /// Whether `token` is the valid token with this purpose for `email`, which
+    /// can then not be used again.
+    pub fn redeem(
+        &self,
+        email: &Email,
+        purpose: Purpose,
+        token: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        Ok(self.take(email, purpose, token)?.is_some())
+    }
+
+    /// Redeems `token` like `redeem`, returning the target of the email
+    /// change it was issued for.
+    pub fn redeem_moving(
+        &self,
+        email: &Email,
+        purpose: Purpose,
+        token: &str,
+    ) -> Result<Option<Email>, Box<dyn Error>> {
+        Ok(self
+            .take(email, purpose, token)?
+            .and_then(|stored| stored.target))
+    }
+
+    fn take(
+        &self,
+        email: &Email,
+        purpose: Purpose,
+        token: &str,
+    ) -> Result<Option<StoredToken>, Box<dyn Error>> {
+        let _lock = self.lock.lock().unwrap();
+        let Some(mut stored) = self.store.get_token(email, purpose)? else {
+            log::error!("No token to redeem");
+            return Ok(None);
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        if stored.used || now >= stored.expires || stored.attempts >= self.config.max_attempts {
+            log::error!("Token used, expired or locked after too many attempts");
+            return Ok(None);
+        }
+
+        let valid = stored.hash == hash(token);
+        if valid {
+            stored.used = true;
+        } else {
+            stored.attempts += 1;
+            log::error!("Wrong token, attempt {}", stored.attempts);
+        }
+        self.store.put_token(&stored)?;
+        Ok(valid.then_some(stored))
+    }
+}
+
+fn hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
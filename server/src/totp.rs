@@ -0,0 +1,67 @@
+use crate::database::UserStore;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::SystemTime;
+use utils::crypto::{totp_code, totp_step};
+use utils::{TotpCredential, User};
+use validation::Email;
+
+/// Shortest secret accepted, as required by RFC 4226.
+const MIN_SECRET_LENGTH: usize = 16;
+
+/// Checks the codes of the authenticator apps.
+///
+/// A code is accepted within `skew` time steps of the current one, and only
+/// once: the last step accepted is stored with the user, so a code seen by
+/// someone else cannot be replayed. The code is checked and its step stored in
+/// one change of the user, so it cannot be used twice at once nor the step be
+/// overwritten by another change.
+pub struct Totp {
+    store: Arc<dyn UserStore>,
+    skew: u64,
+}
+
+impl Totp {
+    pub fn new(store: Arc<dyn UserStore>, skew: u64) -> Totp {
+        Totp { store, skew }
+    }
+
+    /// Credential for `secret`, if `code` shows the app was given it.
+    pub fn enroll(&self, secret: Vec<u8>, code: &str) -> Option<TotpCredential> {
+        if secret.len() < MIN_SECRET_LENGTH {
+            log::error!("TOTP secret of {} bytes is too short", secret.len());
+            return None;
+        }
+        let last_step = self.check(&secret, code, 0)?;
+        Some(TotpCredential { secret, last_step })
+    }
+
+    /// Whether `code` is valid for the authenticator app of `email`, which
+    /// then cannot be used again. Returns the user updated if so.
+    pub fn verify(&self, email: &Email, code: &str) -> Result<Option<User>, Box<dyn Error>> {
+        self.store.modify(email, &mut |user| {
+            let Some(totp) = &mut user.totp else {
+                log::error!("No authenticator app enrolled");
+                return false;
+            };
+            let Some(step) = self.check(&totp.secret, code, totp.last_step) else {
+                return false;
+            };
+            totp.last_step = step;
+            true
+        })
+    }
+
+    /// Time step at which `secret` generates `code`, if it is close enough to
+    /// now and after the step `after`.
+    fn check(&self, secret: &[u8], code: &str, after: u64) -> Option<u64> {
+        let now = totp_step(SystemTime::now());
+        let step = (now.saturating_sub(self.skew)..=now.saturating_add(self.skew))
+            .filter(|step| *step > after)
+            .find(|step| totp_code(secret, *step) == code);
+        if step.is_none() {
+            log::error!("Wrong, expired or already used TOTP code");
+        }
+        step
+    }
+}
@@ -0,0 +1,553 @@
+//! In-process server and scripted clients for the end-to-end tests.
+#![allow(dead_code)]
+
+use client::action::Action;
+use client::authentication::Authenticate;
+use client::config::{Config as ClientConfig, Device, Transport as ClientTransport};
+use client::connection::Connection;
+use client::prompt::Prompt;
+use client::software::SoftwareToken;
+use client::token::HardwareToken;
+use server::config::{Config, Storage, Transport as ServerTransport};
+use server::mailer::MemoryTransport;
+use server::server::Server;
+use std::error::Error;
+use std::fs;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+use utils::crypto::{
+    generate_random_256_bits, srp_client_proof, srp_client_public, totp_code, totp_step,
+};
+use utils::noise::NoiseStream;
+use utils::{
+    EmailData, Error as UtilsError, Request, Response, SecondFactor, SrpProofData, SrpStartData,
+    YubiKeyData,
+};
+use validation::{Email, KeyLabel, Otp, Password, Pin, RecoveryCode, Token};
+
+pub const PIN: &str = "123456";
+
+/// How long the raw connections wait for the server.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub enum Transport {
+    Tls,
+    Noise,
+}
+
+/// Server listening on an ephemeral port, with its database in a temporary
+/// directory and its emails kept in memory.
+pub struct TestServer {
+    pub mailer: Arc<MemoryTransport>,
+    client_config: ClientConfig,
+    shutdown: Option<oneshot::Sender<()>>,
+    /// Receives once the server stopped serving.
+    stopped: Mutex<mpsc::Receiver<()>>,
+    runtime: Option<Runtime>,
+    dir: TempDir,
+}
+
+impl TestServer {
+    pub fn start(transport: Transport) -> TestServer {
+        TestServer::start_with(transport, |_, _| {})
+    }
+
+    /// Starts with the configuration changed by `configure`, which is given
+    /// the temporary directory of the server.
+    pub fn start_with(
+        transport: Transport,
+        configure: impl FnOnce(&mut Config, &Path),
+    ) -> TestServer {
+        let dir = tempfile::tempdir().unwrap();
+        let (server_transport, client_transport) = match transport {
+            Transport::Tls => {
+                let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+                let cert_path = dir.path().join("cert.pem");
+                let key_path = dir.path().join("key.pem");
+                fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+                fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+                (
+                    ServerTransport::Tls {
+                        cert: cert_path.clone(),
+                        key: key_path,
+                    },
+                    ClientTransport::Tls {
+                        server_cert: cert_path,
+                        server_name: "localhost".to_string(),
+                    },
+                )
+            }
+            Transport::Noise => {
+                let (private_key, public_key) = utils::noise::generate_keypair().unwrap();
+                let key_path = dir.path().join("noise.key");
+                fs::write(&key_path, hex::encode(private_key)).unwrap();
+                (
+                    ServerTransport::Noise {
+                        private_key: key_path,
+                    },
+                    ClientTransport::Noise {
+                        server_public_key: hex::encode(public_key),
+                    },
+                )
+            }
+        };
+
+        let mut config = Config {
+            address: "127.0.0.1:0".to_string(),
+            transport: server_transport,
+            storage: Storage::Ron {
+                path: dir.path().join("db.ron"),
+            },
+            ..Config::default()
+        };
+        configure(&mut config, dir.path());
+        let mailer = Arc::new(MemoryTransport::new());
+        let runtime = Server::runtime(&config).unwrap();
+        let server = runtime
+            .block_on(Server::bind(config, mailer.clone()))
+            .unwrap();
+        let address = server.local_addr().unwrap();
+
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let (done, stopped) = mpsc::channel();
+        runtime.spawn(async move {
+            server
+                .serve(async {
+                    let _ = signal.await;
+                })
+                .await
+                .unwrap();
+            let _ = done.send(());
+        });
+
+        TestServer {
+            mailer,
+            client_config: ClientConfig {
+                address: address.to_string(),
+                transport: client_transport,
+                device: Device::Software {
+                    path: None,
+                    pin: PIN.to_string(),
+                },
+            },
+            shutdown: Some(shutdown),
+            stopped: Mutex::new(stopped),
+            runtime: Some(runtime),
+            dir,
+        }
+    }
+
+    /// Asks the server to shut down, without waiting for it.
+    pub fn shut_down(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+
+    /// Whether the server stopped serving within `timeout`.
+    pub fn stopped(&self, timeout: Duration) -> bool {
+        self.stopped.lock().unwrap().recv_timeout(timeout).is_ok()
+    }
+
+    pub fn address(&self) -> &str {
+        &self.client_config.address
+    }
+
+    /// Temporary directory holding the server files.
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn connect(&self) -> Connection {
+        Connection::connect(&self.client_config).unwrap()
+    }
+
+    /// Noise channel to the server without the protocol hello, to send raw
+    /// frames. Reads time out after `READ_TIMEOUT`.
+    pub fn connect_noise(&self) -> NoiseStream<TcpStream> {
+        let ClientTransport::Noise { server_public_key } = &self.client_config.transport else {
+            panic!("Not a Noise server");
+        };
+        let stream = TcpStream::connect(&self.client_config.address).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        NoiseStream::connect(stream, &hex::decode(server_public_key).unwrap()).unwrap()
+    }
+
+    /// Client for `email`, with its own software token.
+    pub fn user(&self, email: &str, password: &str) -> TestUser {
+        TestUser {
+            token: SoftwareToken::new(None, PIN),
+            prompt: Script {
+                email: email.to_string(),
+                password: password.to_string(),
+                new_password: password.to_string(),
+                pin: PIN.to_string(),
+                key_label: "primary".to_string(),
+                token: None,
+                confirm: false,
+                second_factor: SecondFactor::YubiKey,
+                otp: None,
+                totp_secret: None,
+                totp_step: 0,
+                recovery_codes: vec![],
+                recovery_code: None,
+                file: self.dir().join(format!("{}.json", email)),
+                mailer: self.mailer.clone(),
+            },
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shut_down();
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_timeout(Duration::from_secs(5));
+        }
+    }
+}
+
+/// Answers the client prompts with fixed values, with the token found in the
+/// last email received when asked for a token unless `token` is set, as an
+/// authenticator app given the secret shown unless `otp` is set, and with the
+/// next recovery code shown unless `recovery_code` is set.
+pub struct Script {
+    pub email: String,
+    pub password: String,
+    pub new_password: String,
+    pub pin: String,
+    pub key_label: String,
+    pub token: Option<String>,
+    pub confirm: bool,
+    pub second_factor: SecondFactor,
+    pub otp: Option<String>,
+    totp_secret: Option<Vec<u8>>,
+    /// Time step of the last code generated, each code is generated for a
+    /// later step so that none is refused as replayed.
+    totp_step: u64,
+    pub recovery_codes: Vec<String>,
+    pub recovery_code: Option<String>,
+    /// File the data is exported to.
+    pub file: PathBuf,
+    mailer: Arc<MemoryTransport>,
+}
+
+impl Prompt for Script {
+    fn email(&mut self) -> Email {
+        self.email.parse().unwrap()
+    }
+
+    fn password(&mut self) -> Password {
+        self.password.parse().unwrap()
+    }
+
+    fn new_password(&mut self) -> Password {
+        self.new_password.parse().unwrap()
+    }
+
+    fn pin(&mut self) -> Pin {
+        self.pin.parse().unwrap()
+    }
+
+    fn key_label(&mut self) -> KeyLabel {
+        self.key_label.parse().unwrap()
+    }
+
+    fn token(&mut self) -> Token {
+        if let Some(token) = &self.token {
+            return token.parse().unwrap();
+        }
+        self.mailer
+            .mails()
+            .iter()
+            .rev()
+            .filter(|mail| mail.to == self.email)
+            .find_map(|mail| {
+                mail.text
+                    .split_whitespace()
+                    .find_map(|word| word.parse().ok())
+            })
+            .expect("No token received")
+    }
+
+    fn second_factor(&mut self, _: &[SecondFactor]) -> SecondFactor {
+        self.second_factor
+    }
+
+    fn show_totp(&mut self, uri: &str) {
+        let secret = uri
+            .split(['?', '&'])
+            .find_map(|parameter| parameter.strip_prefix("secret="))
+            .expect("No secret in the URI");
+        self.totp_secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret);
+    }
+
+    fn otp(&mut self) -> Otp {
+        if let Some(otp) = &self.otp {
+            return otp.parse().unwrap();
+        }
+        let secret = self.totp_secret.as_ref().expect("No authenticator app");
+        self.totp_step = totp_step(SystemTime::now()).max(self.totp_step + 1);
+        totp_code(secret, self.totp_step).parse().unwrap()
+    }
+
+    fn show_recovery_codes(&mut self, codes: &[String]) {
+        self.recovery_codes = codes.to_vec();
+    }
+
+    fn recovery_code(&mut self) -> RecoveryCode {
+        match &self.recovery_code {
+            Some(code) => code.parse().unwrap(),
+            None => self.recovery_codes.remove(0).parse().unwrap(),
+        }
+    }
+
+    fn confirm(&mut self, _: &str) -> bool {
+        self.confirm
+    }
+    fn file(&mut self) -> PathBuf {
+        self.file.clone()
+    }
+}
+
+/// Runs the client flows as a user.
+pub struct TestUser {
+    pub token: SoftwareToken,
+    pub prompt: Script,
+}
+
+impl TestUser {
+    /// Registers then confirms the email, leaving the user logged out.
+    pub fn register(&mut self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+        self.register_pending(connection)?;
+        self.verify(connection)
+    }
+
+    /// Registers without confirming the email.
+    pub fn register_pending(&mut self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+        Authenticate::Register.perform(connection, &mut self.token, &mut self.prompt)?;
+        Ok(())
+    }
+
+    /// Logs in to confirm the email with the mailed token, mailed again first
+    /// if `prompt.confirm` is set.
+    pub fn verify(&mut self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+        let email =
+            Authenticate::Authenticate.perform(connection, &mut self.token, &mut self.prompt)?;
+        if email.is_some() {
+            return Err("Email already verified".into());
+        }
+        Ok(())
+    }
+
+    pub fn authenticate(&mut self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+        let email =
+            Authenticate::Authenticate.perform(connection, &mut self.token, &mut self.prompt)?;
+        if email.is_none() {
+            return Err("Email not verified".into());
+        }
+        Ok(())
+    }
+
+    /// Runs a logged in action.
+    pub fn perform(
+        &mut self,
+        connection: &mut Connection,
+        action: Action,
+    ) -> Result<(), Box<dyn Error>> {
+        let email = self.prompt.email();
+        action.perform(connection, &mut self.token, &mut self.prompt, &email)?;
+        Ok(())
+    }
+
+    /// Resets the password to `new_password`, which becomes the password used
+    /// by the next flows.
+    pub fn reset_password(
+        &mut self,
+        connection: &mut Connection,
+        new_password: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.prompt.new_password = new_password.to_string();
+        Authenticate::Reset.perform(connection, &mut self.token, &mut self.prompt)?;
+        self.prompt.password = new_password.to_string();
+        Ok(())
+    }
+
+    /// Changes the password to `new_password`, which becomes the password
+    /// used by the next flows.
+    pub fn change_password(
+        &mut self,
+        connection: &mut Connection,
+        new_password: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.prompt.new_password = new_password.to_string();
+        self.perform(connection, Action::ChangePassword)?;
+        self.prompt.password = new_password.to_string();
+        Ok(())
+    }
+
+    /// Enrolls a new key labelled `label`, swapping the token for it when
+    /// asked to like a user inserting another YubiKey, and returns it.
+    pub fn enroll_key(
+        &mut self,
+        connection: &mut Connection,
+        label: &str,
+    ) -> Result<SoftwareToken, Box<dyn Error>> {
+        self.prompt.key_label = label.to_string();
+        let email = self.prompt.email();
+        let mut token = Enrolling {
+            current: &mut self.token,
+            enrolled: None,
+        };
+        Action::EnrollKey.perform(connection, &mut token, &mut self.prompt, &email)?;
+        Ok(token.enrolled.ok_or("No key generated")?)
+    }
+
+    /// Moves the account to `email`, which becomes the email used by the
+    /// next flows.
+    pub fn change_email(
+        &mut self,
+        connection: &mut Connection,
+        email: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let previous = std::mem::replace(&mut self.prompt.email, email.to_string());
+        if let Err(e) = self.perform(connection, Action::ChangeEmail) {
+            self.prompt.email = previous;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Moves the account back to `email` with the token mailed to it, which
+    /// becomes the email used by the next flows.
+    pub fn revert_email(
+        &mut self,
+        connection: &mut Connection,
+        email: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.prompt.email = email.to_string();
+        Authenticate::RevertEmail.perform(connection, &mut self.token, &mut self.prompt)?;
+        Ok(())
+    }
+
+    /// Proves the password without the second factor, returning the challenge
+    /// to sign.
+    pub fn prove_password(
+        &mut self,
+        connection: &mut Connection,
+    ) -> Result<[u8; 16], Box<dyn Error>> {
+        let a = generate_random_256_bits();
+        let Response::SrpChallenge(challenge_data) =
+            connection.request(&Request::StartAuthentication(SrpStartData {
+                email: self.prompt.email(),
+                a_pub: srp_client_public(&a),
+            }))?
+        else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        let proof = srp_client_proof(
+            &a,
+            &self.prompt.password,
+            &challenge_data.salt,
+            &challenge_data.b_pub,
+        )?;
+        connection.request(&Request::ProvePassword(SrpProofData {
+            proof: proof.proof().to_vec(),
+        }))?;
+        Ok(challenge_data.challenge)
+    }
+
+    /// Signs `challenge` with the YubiKey to complete a login.
+    pub fn prove_yubikey(
+        &mut self,
+        connection: &mut Connection,
+        challenge: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let pin = self.prompt.pin();
+        connection.request(&Request::ProveYubiKey(YubiKeyData {
+            yubikey: self.token.sign(&pin, challenge)?,
+        }))?;
+        Ok(())
+    }
+
+    /// Proves the YubiKey again in an authenticated session, so that its
+    /// sensitive actions can be done with another token.
+    pub fn step_up(&mut self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+        let Response::StepUp(step_up_data) = connection.request(&Request::StartStepUp)? else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        let pin = self.prompt.pin();
+        connection.request(&Request::ProveStepUp(YubiKeyData {
+            yubikey: self.token.sign(&pin, &step_up_data.challenge)?,
+        }))?;
+        Ok(())
+    }
+
+    /// Proves the YubiKey to have a reset token mailed, without redeeming it.
+    pub fn request_reset(&mut self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+        let Response::Challenge(challenge_data) =
+            connection.request(&Request::StartReset(EmailData {
+                email: self.prompt.email(),
+            }))?
+        else {
+            return Err(UtilsError::UnexpectedResponse.into());
+        };
+        let pin = self.prompt.pin();
+        connection.request(&Request::ProveYubiKey(YubiKeyData {
+            yubikey: self.token.sign(&pin, &challenge_data.challenge)?,
+        }))?;
+        Ok(())
+    }
+
+    /// Sets the password to `new_password` with the mailed reset token.
+    pub fn redeem_token(
+        &mut self,
+        connection: &mut Connection,
+        new_password: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.prompt.new_password = new_password.to_string();
+        Authenticate::RedeemToken.perform(connection, &mut self.token, &mut self.prompt)?;
+        self.prompt.password = new_password.to_string();
+        Ok(())
+    }
+}
+
+/// Token of a user enrolling a key: the current one until a key is
+/// generated, then the one `enrolled`.
+struct Enrolling<'a> {
+    current: &'a mut SoftwareToken,
+    enrolled: Option<SoftwareToken>,
+}
+
+impl HardwareToken for Enrolling<'_> {
+    fn generate(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut enrolled = SoftwareToken::new(None, PIN);
+        let public_key = enrolled.generate()?;
+        self.enrolled = Some(enrolled);
+        Ok(public_key)
+    }
+
+    fn sign(&mut self, pin: &Pin, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match &mut self.enrolled {
+            Some(enrolled) => enrolled.sign(pin, message),
+            None => self.current.sign(pin, message),
+        }
+    }
+}
+
+pub fn logout(connection: &mut Connection) {
+    connection.request(&Request::Logout).unwrap();
+}
+
+/// Protocol error sent back by the server.
+pub fn server_error(result: Result<(), Box<dyn Error>>) -> UtilsError {
+    *result
+        .expect_err("Request succeeded")
+        .downcast::<UtilsError>()
+        .expect("Not a protocol error")
+}
@@ -0,0 +1,1250 @@
+mod common;
+
+use client::action::Action;
+use client::prompt::Prompt;
+use client::software::SoftwareToken;
+use client::token::HardwareToken;
+use common::{logout, server_error, TestServer, Transport, PIN};
+use server::config::{Encryption, Key, Limits, Storage, Transport as ServerTransport};
+use server::database::{self, Subject};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use utils::crypto::{generate_salt, srp_verifier};
+use utils::{
+    Error as UtilsError, KeyData, PasswordData, Request, Response, SecondFactor, TotpEnrollData,
+    User, YubiKeyData,
+};
+use validation::Email;
+
+const EMAIL: &str = "alice@example.com";
+const PASSWORD: &str = "Password1!";
+
+#[test]
+fn register_and_authenticate_over_noise() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+}
+
+#[test]
+fn register_and_authenticate_over_tls() {
+    let server = TestServer::start(Transport::Tls);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+}
+
+#[test]
+fn accounts_outlive_connections() {
+    let server = TestServer::start(Transport::Noise);
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut server.connect()).unwrap();
+
+    alice.authenticate(&mut server.connect()).unwrap();
+}
+
+#[test]
+fn reset_password_with_mailed_token() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+
+    alice
+        .reset_password(&mut connection, "NewPassword2?")
+        .unwrap();
+    let mails = server.mailer.mails();
+    let [.., reset, changed] = mails.as_slice() else {
+        panic!("No reset emails");
+    };
+    assert!(reset.subject.starts_with("Reset"));
+    assert_eq!(changed.subject, "Your SEC password was changed");
+    assert!(reset.text.contains("127.0.0.1"));
+    assert!(reset.html.contains("<html>"));
+    logout(&mut connection);
+
+    alice.authenticate(&mut connection).unwrap();
+    logout(&mut connection);
+    alice.prompt.password = PASSWORD.to_string();
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::AuthFailed
+    ));
+}
+
+#[test]
+fn wrong_password_is_rejected() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+
+    alice.prompt.password = "Password2!".to_string();
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::AuthFailed
+    ));
+}
+
+#[test]
+fn unknown_email_is_rejected() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut bob = server.user("bob@example.com", PASSWORD);
+
+    assert!(matches!(
+        server_error(bob.authenticate(&mut connection)),
+        UtilsError::AuthFailed
+    ));
+}
+
+#[test]
+fn other_token_is_rejected() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+
+    alice.token = SoftwareToken::new(None, PIN);
+    alice.token.generate().unwrap();
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::TwoFAFailed
+    ));
+}
+
+#[test]
+fn notifications_are_mailed() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    let subjects: Vec<_> = server
+        .mailer
+        .mails()
+        .into_iter()
+        .map(|mail| mail.subject)
+        .collect();
+    assert_eq!(
+        subjects,
+        ["Confirm your SEC email", "New sign-in to your SEC account"]
+    );
+}
+
+#[test]
+fn reset_token_is_redeemed_from_a_new_connection() {
+    let server = TestServer::start(Transport::Noise);
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut server.connect()).unwrap();
+
+    alice.request_reset(&mut server.connect()).unwrap();
+    alice
+        .redeem_token(&mut server.connect(), "NewPassword2?")
+        .unwrap();
+    alice.authenticate(&mut server.connect()).unwrap();
+}
+
+#[test]
+fn reset_token_is_redeemed_with_sealed_sqlite_storage() {
+    let server = TestServer::start_with(Transport::Noise, |config, dir| {
+        config.storage = Storage::Sqlite {
+            path: dir.join("users.db"),
+        };
+        config.encryption = Some(Encryption {
+            key: Key::File {
+                path: dir.join("storage.key"),
+            },
+            previous_keys: vec![],
+        });
+    });
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut server.connect()).unwrap();
+
+    alice.request_reset(&mut server.connect()).unwrap();
+    alice
+        .redeem_token(&mut server.connect(), "NewPassword2?")
+        .unwrap();
+    alice.authenticate(&mut server.connect()).unwrap();
+}
+
+/// Registers a user in storage sealed with key A and fails to log in, then
+/// restarts with key B and A as a previous key: the failures still throttle
+/// the user, and the storage then opens with B alone.
+fn storage_key_rotation(storage: fn(&Path) -> Storage) {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = storage(dir.path());
+    let key = |name: &str| Key::File {
+        path: dir.path().join(name),
+    };
+    let start = |encryption: Encryption| {
+        let storage = storage.clone();
+        TestServer::start_with(Transport::Noise, move |config, _| {
+            config.storage = storage;
+            config.encryption = Some(encryption);
+            config.throttling.account = strict();
+        })
+    };
+
+    let server = start(Encryption {
+        key: key("a.key"),
+        previous_keys: vec![],
+    });
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut server.connect()).unwrap();
+    alice.prompt.password = "Password2!".to_string();
+    for _ in 0..2 {
+        assert!(matches!(
+            server_error(alice.authenticate(&mut server.connect())),
+            UtilsError::AuthFailed
+        ));
+    }
+    drop(server);
+
+    let server = start(Encryption {
+        key: key("b.key"),
+        previous_keys: vec![key("a.key")],
+    });
+    alice.prompt.password = PASSWORD.to_string();
+    assert!(matches!(
+        server_error(alice.authenticate(&mut server.connect())),
+        UtilsError::Throttled(1..=60)
+    ));
+    drop(server);
+
+    let store = database::open(
+        &storage,
+        Some(&Encryption {
+            key: key("b.key"),
+            previous_keys: vec![],
+        }),
+    )
+    .unwrap();
+    let email: Email = EMAIL.parse().unwrap();
+    assert!(store.get(&email).unwrap().is_some());
+    assert!(store
+        .get_failures(&Subject::Account(email))
+        .unwrap()
+        .is_some());
+}
+
+/// Checks that a change of a user started during another waits for it, so
+/// that neither is lost.
+fn concurrent_changes_are_kept(storage: impl FnOnce(&Path) -> Storage) {
+    let dir = tempfile::tempdir().unwrap();
+    let store = database::open(&storage(dir.path()), None).unwrap();
+    let email: Email = EMAIL.parse().unwrap();
+    store
+        .insert(&User {
+            email: email.clone(),
+            ..User::default()
+        })
+        .unwrap();
+
+    thread::scope(|scope| {
+        store
+            .modify(&email, &mut |user| {
+                scope.spawn(|| {
+                    store
+                        .modify(&email, &mut |user| {
+                            user.recovery_codes.push(vec![2]);
+                            true
+                        })
+                        .unwrap();
+                });
+                thread::sleep(Duration::from_millis(100));
+                user.recovery_codes.push(vec![1]);
+                true
+            })
+            .unwrap();
+    });
+    let user = store.get(&email).unwrap().unwrap();
+    assert_eq!(user.recovery_codes, vec![vec![1], vec![2]]);
+}
+
+#[test]
+fn ron_storage_keeps_concurrent_changes() {
+    concurrent_changes_are_kept(|dir| Storage::Ron {
+        path: dir.join("db.ron"),
+    });
+}
+
+#[test]
+fn sqlite_storage_keeps_concurrent_changes() {
+    concurrent_changes_are_kept(|dir| Storage::Sqlite {
+        path: dir.join("users.db"),
+    });
+}
+
+#[test]
+fn ron_storage_is_resealed_with_the_new_key() {
+    storage_key_rotation(|dir| Storage::Ron {
+        path: dir.join("db.ron"),
+    });
+}
+
+#[test]
+fn sqlite_storage_is_resealed_with_the_new_key() {
+    storage_key_rotation(|dir| Storage::Sqlite {
+        path: dir.join("users.db"),
+    });
+}
+
+#[test]
+fn reset_token_is_single_use() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+
+    alice
+        .reset_password(&mut connection, "NewPassword2?")
+        .unwrap();
+    logout(&mut connection);
+    assert!(matches!(
+        server_error(alice.redeem_token(&mut connection, "NewPassword3?")),
+        UtilsError::UuidFailed
+    ));
+}
+
+#[test]
+fn reset_token_guesses_are_bounded() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.request_reset(&mut connection).unwrap();
+
+    alice.prompt.token = Some(uuid::Uuid::new_v4().to_string());
+    for _ in 0..5 {
+        assert!(matches!(
+            server_error(alice.redeem_token(&mut connection, "NewPassword2?")),
+            UtilsError::UuidFailed
+        ));
+    }
+    alice.prompt.token = None;
+    assert!(matches!(
+        server_error(alice.redeem_token(&mut connection, "NewPassword2?")),
+        UtilsError::UuidFailed
+    ));
+}
+
+#[test]
+fn expired_reset_token_is_rejected() {
+    let server = TestServer::start_with(Transport::Noise, |config, _| config.tokens.lifetime = 0);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+
+    alice.request_reset(&mut connection).unwrap();
+    assert!(matches!(
+        server_error(alice.redeem_token(&mut connection, "NewPassword2?")),
+        UtilsError::UuidFailed
+    ));
+}
+
+#[test]
+fn failures_are_only_kept_for_accounts_and_until_forgotten() {
+    let server = TestServer::start_with(Transport::Noise, |config, dir| {
+        config.storage = Storage::Sqlite {
+            path: dir.join("users.db"),
+        };
+        config.throttling.forget_after = 0;
+    });
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    let store = database::open(
+        &Storage::Sqlite {
+            path: server.dir().join("users.db"),
+        },
+        None,
+    )
+    .unwrap();
+    let account = |email: &str| Subject::Account(email.parse().unwrap());
+
+    alice.prompt.password = "Password2!".to_string();
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::AuthFailed
+    ));
+    assert!(store.get_failures(&account(EMAIL)).unwrap().is_some());
+
+    assert!(matches!(
+        server_error(
+            server
+                .user("bob@example.com", PASSWORD)
+                .authenticate(&mut connection)
+        ),
+        UtilsError::AuthFailed
+    ));
+    assert!(store
+        .get_failures(&account("bob@example.com"))
+        .unwrap()
+        .is_none());
+    assert!(store.get_failures(&account(EMAIL)).unwrap().is_none());
+    assert!(store
+        .get_failures(&Subject::Address("127.0.0.1".parse().unwrap()))
+        .unwrap()
+        .is_some());
+}
+
+/// One free attempt, then a minute of delay.
+fn strict() -> Limits {
+    Limits {
+        free_attempts: 1,
+        base_delay: 60,
+        lockout_after: 10,
+        lockout: 900,
+    }
+}
+
+#[test]
+fn failures_throttle_the_account() {
+    let server = TestServer::start_with(Transport::Noise, |config, _| {
+        config.throttling.account = strict()
+    });
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+
+    alice.prompt.password = "Password2!".to_string();
+    for _ in 0..2 {
+        assert!(matches!(
+            server_error(alice.authenticate(&mut connection)),
+            UtilsError::AuthFailed
+        ));
+    }
+    alice.prompt.password = PASSWORD.to_string();
+    assert!(matches!(
+        server_error(alice.authenticate(&mut server.connect())),
+        UtilsError::Throttled(1..=60)
+    ));
+}
+
+#[test]
+fn failures_throttle_the_address() {
+    let server = TestServer::start_with(Transport::Noise, |config, _| {
+        config.throttling.address = strict()
+    });
+    let mut connection = server.connect();
+
+    for email in ["bob@example.com", "carol@example.com"] {
+        assert!(matches!(
+            server_error(server.user(email, PASSWORD).authenticate(&mut connection)),
+            UtilsError::AuthFailed
+        ));
+    }
+    assert!(matches!(
+        server_error(server.user(EMAIL, PASSWORD).authenticate(&mut connection)),
+        UtilsError::Throttled(1..=60)
+    ));
+}
+
+#[test]
+fn admin_unlocks_the_account() {
+    let server = TestServer::start_with(Transport::Noise, |config, dir| {
+        config.storage = Storage::Sqlite {
+            path: dir.join("users.db"),
+        };
+        config.throttling.account = strict();
+    });
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+
+    alice.prompt.password = "Password2!".to_string();
+    for _ in 0..2 {
+        server_error(alice.authenticate(&mut connection));
+    }
+    let storage = Storage::Sqlite {
+        path: server.dir().join("users.db"),
+    };
+    let store = database::open(&storage, None).unwrap();
+    assert!(store
+        .clear_failures(&Subject::Account(EMAIL.parse().unwrap()))
+        .unwrap());
+
+    alice.prompt.password = PASSWORD.to_string();
+    alice.authenticate(&mut connection).unwrap();
+}
+
+#[test]
+fn unverified_account_cannot_authenticate() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register_pending(&mut connection).unwrap();
+
+    alice.prompt.token = Some(uuid::Uuid::new_v4().to_string());
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::UuidFailed
+    ));
+    alice.prompt.token = None;
+    alice.verify(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+}
+
+#[test]
+fn verification_is_mailed_again() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register_pending(&mut connection).unwrap();
+    let first = server.mailer.last_mail(EMAIL).unwrap().text;
+
+    alice.prompt.confirm = true;
+    alice.prompt.token = first
+        .split_whitespace()
+        .find(|word| uuid::Uuid::parse_str(word).is_ok())
+        .map(str::to_string);
+    assert!(matches!(
+        server_error(alice.verify(&mut connection)),
+        UtilsError::UuidFailed
+    ));
+    assert_eq!(server.mailer.mails().len(), 2);
+
+    alice.prompt.token = None;
+    alice.verify(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+}
+
+#[test]
+fn expired_verification_token_is_rejected() {
+    let server = TestServer::start_with(Transport::Noise, |config, _| {
+        config.tokens.verification_lifetime = 0
+    });
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register_pending(&mut connection).unwrap();
+
+    assert!(matches!(
+        server_error(alice.verify(&mut connection)),
+        UtilsError::UuidFailed
+    ));
+}
+
+#[test]
+fn pending_account_is_replaced_by_a_new_registration() {
+    let server = TestServer::start(Transport::Noise);
+    let mut mallory = server.user(EMAIL, "Mallory1!");
+    mallory.register_pending(&mut server.connect()).unwrap();
+
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut server.connect()).unwrap();
+    alice.authenticate(&mut server.connect()).unwrap();
+    assert!(matches!(
+        server_error(mallory.authenticate(&mut server.connect())),
+        UtilsError::AuthFailed
+    ));
+}
+
+#[test]
+fn register_with_authenticator_app() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.prompt.second_factor = SecondFactor::Totp;
+
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+}
+
+#[test]
+fn totp_code_cannot_be_replayed() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.prompt.second_factor = SecondFactor::Totp;
+    alice.register(&mut connection).unwrap();
+
+    alice.prompt.otp = Some(alice.prompt.otp().to_string());
+    alice.authenticate(&mut connection).unwrap();
+    logout(&mut connection);
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::TotpFailed
+    ));
+}
+
+#[test]
+fn second_factor_is_chosen_at_login() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    alice.perform(&mut connection, Action::EnrollTotp).unwrap();
+    assert!(server
+        .mailer
+        .last_mail(EMAIL)
+        .unwrap()
+        .text
+        .contains("Authenticator app (TOTP)"));
+    logout(&mut connection);
+
+    alice.prompt.second_factor = SecondFactor::Totp;
+    alice.authenticate(&mut connection).unwrap();
+    logout(&mut connection);
+    alice.prompt.second_factor = SecondFactor::YubiKey;
+    alice.authenticate(&mut connection).unwrap();
+}
+
+#[test]
+fn reset_password_with_authenticator_app() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.prompt.second_factor = SecondFactor::Totp;
+    alice.register(&mut connection).unwrap();
+
+    alice
+        .reset_password(&mut connection, "NewPassword2?")
+        .unwrap();
+}
+
+#[test]
+fn recovery_code_replaces_the_second_factor_once() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    assert_eq!(alice.prompt.recovery_codes.len(), 10);
+
+    alice.token = SoftwareToken::new(None, PIN);
+    alice.prompt.second_factor = SecondFactor::RecoveryCode;
+    alice.prompt.recovery_code = Some(alice.prompt.recovery_codes[0].to_uppercase());
+    alice.authenticate(&mut connection).unwrap();
+    logout(&mut connection);
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::RecoveryCodeFailed
+    ));
+}
+
+#[test]
+fn reset_password_with_recovery_code() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+
+    alice.token = SoftwareToken::new(None, PIN);
+    alice.prompt.second_factor = SecondFactor::RecoveryCode;
+    alice
+        .reset_password(&mut connection, "NewPassword2?")
+        .unwrap();
+}
+
+#[test]
+fn regenerated_recovery_codes_replace_the_previous_ones() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    let previous = alice.prompt.recovery_codes.clone();
+    alice.authenticate(&mut connection).unwrap();
+
+    alice
+        .perform(&mut connection, Action::RegenerateRecoveryCodes)
+        .unwrap();
+    assert_ne!(alice.prompt.recovery_codes, previous);
+    logout(&mut connection);
+
+    alice.prompt.second_factor = SecondFactor::RecoveryCode;
+    alice.prompt.recovery_code = Some(previous[0].clone());
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::RecoveryCodeFailed
+    ));
+    alice.prompt.recovery_code = None;
+    alice.authenticate(&mut connection).unwrap();
+}
+
+#[test]
+fn backup_key_proves_the_second_factor() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    let backup = alice.enroll_key(&mut connection, "backup").unwrap();
+    logout(&mut connection);
+    assert_eq!(
+        server.mailer.last_mail(EMAIL).unwrap().subject,
+        "Second factor added to your SEC account"
+    );
+
+    let primary = std::mem::replace(&mut alice.token, backup);
+
+    alice.authenticate(&mut connection).unwrap();
+    let Response::Keys(keys_data) = connection.request(&Request::ListKeys).unwrap() else {
+        panic!("No keys listed");
+    };
+    let labels: Vec<&str> = keys_data
+        .keys
+        .iter()
+        .map(|key| key.label.as_str())
+        .collect();
+    assert_eq!(labels, ["primary", "backup"]);
+    assert!(keys_data.keys[0].last_used.is_some());
+    assert!(keys_data.keys[1].last_used.is_some());
+    logout(&mut connection);
+
+    alice.token = primary;
+    alice.authenticate(&mut connection).unwrap();
+}
+
+#[test]
+fn key_is_enrolled_after_a_step_up_with_the_key_in_use() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    let backup = alice.enroll_key(&mut connection, "backup").unwrap();
+    alice.step_up(&mut connection).unwrap();
+    alice.token = backup;
+    alice.step_up(&mut connection).unwrap();
+}
+
+#[test]
+fn key_labels_are_unique() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    assert!(matches!(
+        server_error(alice.enroll_key(&mut connection, "primary").map(|_| ())),
+        UtilsError::InvalidKey
+    ));
+}
+
+#[test]
+fn revoked_key_is_refused_but_not_the_last_one() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    assert!(matches!(
+        server_error(alice.perform(&mut connection, Action::RevokeKey)),
+        UtilsError::LastKey
+    ));
+    alice.prompt.key_label = "unknown".to_string();
+    assert!(matches!(
+        server_error(alice.perform(&mut connection, Action::RevokeKey)),
+        UtilsError::UnknownKey
+    ));
+
+    let backup = alice.enroll_key(&mut connection, "backup").unwrap();
+    let primary = std::mem::replace(&mut alice.token, backup);
+    alice.prompt.key_label = "primary".to_string();
+    alice.perform(&mut connection, Action::RevokeKey).unwrap();
+    logout(&mut connection);
+    assert_eq!(
+        server.mailer.last_mail(EMAIL).unwrap().subject,
+        "Second factor removed from your SEC account"
+    );
+
+    alice.token = primary;
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::TwoFAFailed
+    ));
+}
+
+#[test]
+fn key_revoked_during_a_login_is_refused() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+    let backup = alice.enroll_key(&mut connection, "backup").unwrap();
+    let primary = std::mem::replace(&mut alice.token, backup);
+
+    let mut revoked = server.connect();
+    let revoked_challenge = alice.prove_password(&mut revoked).unwrap();
+    let mut kept = server.connect();
+    let kept_challenge = alice.prove_password(&mut kept).unwrap();
+    alice.perform(&mut connection, Action::RevokeKey).unwrap();
+
+    assert!(matches!(
+        server_error(alice.prove_yubikey(&mut revoked, &revoked_challenge)),
+        UtilsError::TwoFAFailed
+    ));
+    alice.token = primary;
+    alice.prove_yubikey(&mut kept, &kept_challenge).unwrap();
+    let Response::Keys(keys_data) = kept.request(&Request::ListKeys).unwrap() else {
+        panic!("No keys listed");
+    };
+    assert_eq!(keys_data.keys.len(), 1);
+    assert_eq!(keys_data.keys[0].label.as_str(), "primary");
+}
+
+#[test]
+fn disabling_2fa_needs_a_step_up() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    assert!(matches!(
+        server_error(connection.request(&Request::Switch2FA).map(|_| ())),
+        UtilsError::StepUpRequired
+    ));
+    alice.perform(&mut connection, Action::Switch2FA).unwrap();
+    let Response::Switched2FA(switch_2fa) = connection.request(&Request::Switch2FA).unwrap() else {
+        panic!("2FA not switched");
+    };
+    assert!(switch_2fa.two_f_a);
+}
+
+#[test]
+fn step_up_needs_an_enrolled_key() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    alice.token = SoftwareToken::new(None, PIN);
+    alice.token.generate().unwrap();
+    assert!(matches!(
+        server_error(alice.perform(&mut connection, Action::Switch2FA)),
+        UtilsError::TwoFAFailed
+    ));
+    assert!(matches!(
+        server_error(
+            connection
+                .request(&Request::ProveStepUp(YubiKeyData { yubikey: vec![] }))
+                .map(|_| ())
+        ),
+        UtilsError::UnexpectedRequest
+    ));
+}
+
+#[test]
+fn enrolling_a_factor_needs_a_step_up() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    let key_data = KeyData {
+        label: "backup".parse().unwrap(),
+        public_key: SoftwareToken::new(None, PIN).generate().unwrap(),
+    };
+    let totp_data = TotpEnrollData {
+        secret: vec![0; 20],
+        code: "123456".parse().unwrap(),
+    };
+    for request in [
+        Request::EnrollKey(key_data),
+        Request::EnrollTotp(totp_data),
+        Request::RegenerateRecoveryCodes,
+    ] {
+        assert!(matches!(
+            server_error(connection.request(&request).map(|_| ())),
+            UtilsError::StepUpRequired
+        ));
+    }
+}
+
+#[test]
+fn step_up_with_authenticator_app() {
+    // Registration, login and step-up each use a code of a later time step.
+    let server = TestServer::start_with(Transport::Noise, |config, _| config.totp_skew = 2);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.prompt.second_factor = SecondFactor::Totp;
+    alice.register(&mut connection).unwrap();
+
+    alice.prompt.otp = Some(alice.prompt.otp().to_string());
+    alice.authenticate(&mut connection).unwrap();
+    assert!(matches!(
+        server_error(alice.change_password(&mut connection, "NewPassword2?")),
+        UtilsError::TotpFailed
+    ));
+
+    alice.prompt.otp = None;
+    alice
+        .change_password(&mut connection, "NewPassword2?")
+        .unwrap();
+    alice.prompt.confirm = true;
+    alice
+        .perform(&mut connection, Action::DeleteAccount)
+        .unwrap();
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::AuthFailed
+    ));
+}
+
+#[test]
+fn step_up_with_recovery_code() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    alice.prompt.second_factor = SecondFactor::RecoveryCode;
+    let used = alice.prompt.recovery_codes[0].clone();
+    alice
+        .perform(&mut connection, Action::RegenerateRecoveryCodes)
+        .unwrap();
+    assert!(!alice.prompt.recovery_codes.contains(&used));
+    logout(&mut connection);
+
+    alice.prompt.recovery_code = Some(used);
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::RecoveryCodeFailed
+    ));
+}
+
+#[test]
+fn step_up_expires_after_its_window() {
+    let server = TestServer::start_with(Transport::Noise, |config, _| config.step_up_window = 0);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    assert!(matches!(
+        server_error(alice.perform(&mut connection, Action::Switch2FA)),
+        UtilsError::StepUpRequired
+    ));
+}
+
+#[test]
+fn password_change_ends_the_other_sessions() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut other = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+    alice.authenticate(&mut other).unwrap();
+
+    alice
+        .change_password(&mut connection, "NewPassword2?")
+        .unwrap();
+    assert_eq!(
+        server.mailer.last_mail(EMAIL).unwrap().subject,
+        "Your SEC password was changed"
+    );
+    assert!(matches!(
+        server_error(other.request(&Request::ListKeys).map(|_| ())),
+        UtilsError::SessionExpired
+    ));
+    connection.request(&Request::ListKeys).unwrap();
+    logout(&mut connection);
+
+    alice.authenticate(&mut connection).unwrap();
+    logout(&mut connection);
+    alice.prompt.password = PASSWORD.to_string();
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::AuthFailed
+    ));
+}
+
+#[test]
+fn password_change_failures_throttle_the_account() {
+    let server = TestServer::start_with(Transport::Noise, |config, _| {
+        config.throttling.account = strict()
+    });
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    alice.prompt.password = "Password2!".to_string();
+    for _ in 0..2 {
+        assert!(matches!(
+            server_error(alice.change_password(&mut connection, "NewPassword2?")),
+            UtilsError::AuthFailed
+        ));
+    }
+    alice.prompt.password = PASSWORD.to_string();
+    assert!(matches!(
+        server_error(alice.change_password(&mut connection, "NewPassword2?")),
+        UtilsError::Throttled(1..=60)
+    ));
+    connection.request(&Request::ListKeys).unwrap();
+}
+
+#[test]
+fn password_change_needs_the_current_password() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    alice.prompt.password = "Password2!".to_string();
+    assert!(matches!(
+        server_error(alice.change_password(&mut connection, "NewPassword2?")),
+        UtilsError::AuthFailed
+    ));
+    connection.request(&Request::ListKeys).unwrap();
+    logout(&mut connection);
+
+    alice.prompt.password = PASSWORD.to_string();
+    alice.authenticate(&mut connection).unwrap();
+}
+
+const NEW_EMAIL: &str = "alice@example.org";
+
+#[test]
+fn email_change_moves_the_account() {
+    let server = TestServer::start_with(Transport::Noise, |config, dir| {
+        config.storage = Storage::Sqlite {
+            path: dir.join("users.db"),
+        };
+    });
+    let mut connection = server.connect();
+    let mut other = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+    alice.authenticate(&mut other).unwrap();
+
+    alice.change_email(&mut connection, NEW_EMAIL).unwrap();
+    assert_eq!(
+        server.mailer.last_mail(EMAIL).unwrap().subject,
+        "The email of your SEC account was changed"
+    );
+    assert!(matches!(
+        server_error(other.request(&Request::ListKeys).map(|_| ())),
+        UtilsError::SessionExpired
+    ));
+    logout(&mut connection);
+
+    alice.authenticate(&mut connection).unwrap();
+    logout(&mut connection);
+    alice.prompt.email = EMAIL.to_string();
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::AuthFailed
+    ));
+}
+
+#[test]
+fn email_change_is_reverted_from_the_previous_email() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+    alice.change_email(&mut connection, NEW_EMAIL).unwrap();
+
+    let mut reverting = server.connect();
+    alice.revert_email(&mut reverting, EMAIL).unwrap();
+    assert!(matches!(
+        server_error(connection.request(&Request::ListKeys).map(|_| ())),
+        UtilsError::SessionExpired
+    ));
+
+    // The revert token proves the email only, not enough to set a password.
+    let salt = generate_salt();
+    let verifier = srp_verifier("NewPassword2?", &salt).unwrap();
+    let set_password = Request::SetPassword(PasswordData { salt, verifier });
+    assert!(matches!(
+        server_error(reverting.request(&set_password).map(|_| ())),
+        UtilsError::UnexpectedRequest
+    ));
+    alice.authenticate(&mut reverting).unwrap();
+    logout(&mut reverting);
+    alice
+        .reset_password(&mut reverting, "NewPassword2?")
+        .unwrap();
+    alice.prompt.email = NEW_EMAIL.to_string();
+    assert!(matches!(
+        server_error(alice.authenticate(&mut server.connect())),
+        UtilsError::AuthFailed
+    ));
+}
+
+#[test]
+fn email_change_to_an_existing_account_is_not_confirmed() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut bob = server.user(NEW_EMAIL, PASSWORD);
+    bob.register(&mut server.connect()).unwrap();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+    let mails = server.mailer.mails().len();
+
+    assert!(matches!(
+        server_error(alice.change_email(&mut connection, NEW_EMAIL)),
+        UtilsError::UuidFailed
+    ));
+    assert_eq!(server.mailer.mails().len(), mails);
+    connection.request(&Request::ListKeys).unwrap();
+}
+
+#[test]
+fn data_is_exported_without_secrets() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    alice.perform(&mut connection, Action::ExportData).unwrap();
+    let document = std::fs::read_to_string(&alice.prompt.file).unwrap();
+    let export: serde_json::Value = serde_json::from_str(&document).unwrap();
+    assert_eq!(export["email"], EMAIL);
+    assert_eq!(export["verified"], true);
+    assert_eq!(export["yubikeys"][0]["label"], "primary");
+    assert_eq!(export["records"]["tokens"][0]["purpose"], "Verify");
+    assert_eq!(export["records"]["tokens"][0]["used"], true);
+    assert!(export.get("verifier").is_none());
+    assert!(export.get("salt").is_none());
+}
+
+#[test]
+fn account_deletion_needs_a_step_up_and_ends_the_sessions() {
+    let server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+    let mut other = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+    alice.authenticate(&mut other).unwrap();
+
+    assert!(matches!(
+        server_error(connection.request(&Request::DeleteAccount).map(|_| ())),
+        UtilsError::StepUpRequired
+    ));
+    alice
+        .perform(&mut connection, Action::DeleteAccount)
+        .unwrap();
+    connection.request(&Request::ListKeys).unwrap();
+
+    alice.prompt.confirm = true;
+    alice
+        .perform(&mut connection, Action::DeleteAccount)
+        .unwrap();
+    assert_eq!(
+        server.mailer.last_mail(EMAIL).unwrap().subject,
+        "Your SEC account was deleted"
+    );
+    assert!(matches!(
+        server_error(other.request(&Request::ListKeys).map(|_| ())),
+        UtilsError::SessionExpired
+    ));
+    assert!(matches!(
+        server_error(alice.authenticate(&mut connection)),
+        UtilsError::AuthFailed
+    ));
+
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+}
+
+/// The revert token mailed to the previous email of a deleted account must not
+/// move the account registered later with its email.
+fn revert_token_is_deleted_with_the_account(storage: fn(&Path) -> Storage) {
+    let server = TestServer::start_with(Transport::Noise, move |config, dir| {
+        config.storage = storage(dir)
+    });
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+    alice.change_email(&mut connection, NEW_EMAIL).unwrap();
+    alice.prompt.confirm = true;
+    alice
+        .perform(&mut connection, Action::DeleteAccount)
+        .unwrap();
+
+    let mut bob = server.user(NEW_EMAIL, "Password2!");
+    bob.register(&mut connection).unwrap();
+    assert!(matches!(
+        server_error(alice.revert_email(&mut server.connect(), EMAIL)),
+        UtilsError::UuidFailed
+    ));
+    bob.authenticate(&mut connection).unwrap();
+}
+
+#[test]
+fn ron_revert_token_is_deleted_with_the_account() {
+    revert_token_is_deleted_with_the_account(|dir| Storage::Ron {
+        path: dir.join("db.ron"),
+    });
+}
+
+#[test]
+fn sqlite_revert_token_is_deleted_with_the_account() {
+    revert_token_is_deleted_with_the_account(|dir| Storage::Sqlite {
+        path: dir.join("users.db"),
+    });
+}
+
+#[cfg(unix)]
+#[test]
+fn generated_keys_are_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = TestServer::start_with(Transport::Noise, |config, dir| {
+        config.transport = ServerTransport::Noise {
+            private_key: dir.join("generated.key"),
+        };
+        config.encryption = Some(Encryption {
+            key: Key::File {
+                path: dir.join("storage.key"),
+            },
+            previous_keys: vec![],
+        });
+    });
+    for key in ["generated.key", "storage.key"] {
+        let mode = std::fs::metadata(server.dir().join(key))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600, "{}", key);
+    }
+}
+
+#[test]
+fn software_key_file_is_private_and_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("token.key");
+    let mut token = SoftwareToken::new(Some(path.clone()), PIN);
+    token.generate().unwrap();
+    let key = std::fs::read_to_string(&path).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    let mut other = SoftwareToken::new(Some(path.clone()), PIN);
+    assert!(other.generate().is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), key);
+}
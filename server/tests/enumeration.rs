@@ -0,0 +1,144 @@
+mod common;
+
+use client::connection::Connection;
+use client::software::SoftwareToken;
+use client::token::HardwareToken;
+use common::{TestServer, Transport, PIN};
+use utils::crypto::{generate_random_256_bits, generate_salt, srp_client_public, srp_verifier};
+use utils::{EmailData, KeyData, RegisterData, Request, Response, SrpStartData, YubiKeyData};
+
+const EMAIL: &str = "alice@example.com";
+const UNKNOWN: &str = "bob@example.com";
+const PASSWORD: &str = "Password1!";
+
+/// Sends a request and returns the response as received, errors included.
+fn exchange(connection: &mut Connection, request: &Request) -> Response {
+    connection.send(request).unwrap();
+    connection.receive().unwrap()
+}
+
+/// Response as printed, except for the random recovery codes.
+fn shape(response: Response) -> String {
+    match response {
+        Response::RecoveryCodes(codes_data) => {
+            format!("RecoveryCodes({} codes)", codes_data.codes.len())
+        }
+        response => format!("{:?}", response),
+    }
+}
+
+/// Responses to a whole registration then to a request only allowed once
+/// logged in, shaped.
+fn register(server: &TestServer, email: &str) -> Vec<String> {
+    let mut connection = server.connect();
+    let mut token = SoftwareToken::new(None, PIN);
+    let salt = generate_salt();
+    let verifier = srp_verifier(PASSWORD, &salt).unwrap();
+
+    [
+        Request::Register(RegisterData {
+            email: email.parse().unwrap(),
+            salt,
+            verifier,
+        }),
+        Request::RegisterYubiKey(KeyData {
+            label: "primary".parse().unwrap(),
+            public_key: token.generate().unwrap(),
+        }),
+        Request::Switch2FA,
+    ]
+    .iter()
+    .map(|request| shape(exchange(&mut connection, request)))
+    .collect()
+}
+
+/// Responses to a reset proven with a key of another account, as printed
+/// except for the random challenge.
+fn reset(server: &TestServer, email: &str) -> Vec<String> {
+    let mut connection = server.connect();
+    let mut token = SoftwareToken::new(None, PIN);
+    token.generate().unwrap();
+
+    let Response::Challenge(challenge_data) = exchange(
+        &mut connection,
+        &Request::StartReset(EmailData {
+            email: email.parse().unwrap(),
+        }),
+    ) else {
+        panic!("No challenge for {}", email);
+    };
+    let signature = token
+        .sign(&PIN.parse().unwrap(), &challenge_data.challenge)
+        .unwrap();
+    let response = exchange(
+        &mut connection,
+        &Request::ProveYubiKey(YubiKeyData { yubikey: signature }),
+    );
+    vec!["Challenge".to_string(), format!("{:?}", response)]
+}
+
+#[test]
+fn registration_does_not_reveal_accounts() {
+    let server = TestServer::start(Transport::Noise);
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut server.connect()).unwrap();
+
+    assert_eq!(register(&server, EMAIL), register(&server, UNKNOWN));
+
+    assert!(server
+        .mailer
+        .last_mail(EMAIL)
+        .unwrap()
+        .subject
+        .contains("already exists"));
+    assert!(server
+        .mailer
+        .last_mail(UNKNOWN)
+        .unwrap()
+        .subject
+        .starts_with("Confirm"));
+    alice.authenticate(&mut server.connect()).unwrap();
+}
+
+/// Salt sent at the start of a login.
+fn login_salt(server: &TestServer, email: &str) -> String {
+    let response = exchange(
+        &mut server.connect(),
+        &Request::StartAuthentication(SrpStartData {
+            email: email.parse().unwrap(),
+            a_pub: srp_client_public(&generate_random_256_bits()),
+        }),
+    );
+    let Response::SrpChallenge(challenge_data) = response else {
+        panic!("No SRP challenge: {:?}", response);
+    };
+    challenge_data.salt
+}
+
+#[test]
+fn login_salt_does_not_reveal_accounts() {
+    let server = TestServer::start(Transport::Noise);
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut server.connect()).unwrap();
+
+    for email in [EMAIL, UNKNOWN] {
+        let salt = login_salt(&server, email);
+        assert_eq!(login_salt(&server, email), salt, "{}", email);
+        assert_eq!(salt.len(), generate_salt().len(), "{}", email);
+    }
+    assert_ne!(
+        login_salt(&server, UNKNOWN),
+        login_salt(&server, "carol@example.com")
+    );
+}
+
+#[test]
+fn reset_does_not_reveal_accounts() {
+    let server = TestServer::start(Transport::Noise);
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut server.connect()).unwrap();
+    let mails = server.mailer.mails().len();
+
+    assert_eq!(reset(&server, EMAIL), reset(&server, UNKNOWN));
+    assert_eq!(server.mailer.mails().len(), mails);
+}
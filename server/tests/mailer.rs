@@ -0,0 +1,76 @@
+use server::config::{Delivery, Mail as MailConfig};
+use server::mailer::{Mail, Notification, Templates};
+use std::fs;
+use std::path::Path;
+
+fn send(delivery: Delivery) {
+    let mailer = server::mailer::open(&MailConfig {
+        from: "noreply@example.com".to_string(),
+        delivery,
+        ..MailConfig::default()
+    })
+    .unwrap();
+    mailer
+        .send_mail(&Mail {
+            to: "alice@example.com".to_string(),
+            subject: "Reset".to_string(),
+            text: "Token 1234".to_string(),
+            html: "<p>Token 1234</p>".to_string(),
+        })
+        .unwrap();
+}
+
+fn read_only_file(directory: &Path) -> String {
+    let files: Vec<_> = fs::read_dir(directory).unwrap().collect();
+    assert_eq!(files.len(), 1);
+    fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap()
+}
+
+#[test]
+fn file_transport_writes_eml() {
+    let dir = tempfile::tempdir().unwrap();
+    let directory = dir.path().join("mail");
+    send(Delivery::File {
+        directory: directory.clone(),
+    });
+
+    let mail = read_only_file(&directory);
+    assert!(mail.contains("To: alice@example.com"));
+    assert!(mail.contains("Subject: Reset"));
+    assert!(mail.contains("Token 1234"));
+    assert!(mail.contains("Content-Type: multipart/alternative"));
+    assert!(mail.contains("<p>Token 1234</p>"));
+}
+
+#[test]
+fn maildir_transport_delivers_to_new() {
+    let dir = tempfile::tempdir().unwrap();
+    send(Delivery::Maildir {
+        directory: dir.path().to_path_buf(),
+    });
+
+    assert_eq!(fs::read_dir(dir.path().join("tmp")).unwrap().count(), 0);
+    assert!(read_only_file(&dir.path().join("new")).contains("Token 1234"));
+}
+
+#[test]
+fn templates_on_disk_replace_builtin() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("new_login.subject.hbs"),
+        "{{brand}} login from {{ip}}",
+    )
+    .unwrap();
+
+    let templates = Templates::new("Crate", Some(dir.path())).unwrap();
+    let mail = templates
+        .render(
+            "alice@example.com",
+            "127.0.0.1".parse().unwrap(),
+            &Notification::NewLogin,
+        )
+        .unwrap();
+    assert_eq!(mail.subject, "Crate login from 127.0.0.1");
+    assert!(mail.text.contains("alice@example.com"));
+    assert!(mail.html.contains("Crate"));
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use client::software::SoftwareToken;
+use client::token::HardwareToken;
+use common::{TestServer, TestUser, Transport, PIN};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use utils::crypto::{generate_salt, hash_password, srp_verifier};
+use validation::Email;
+
+const EMAIL: &str = "alice@example.com";
+const PASSWORD: &str = "Password1!";
+
+/// Users file as written by the builds before schema versions.
+#[derive(Serialize)]
+struct DocumentV0 {
+    data: HashMap<Email, UserV0>,
+}
+
+#[derive(Serialize)]
+struct UserV0 {
+    email: Email,
+    salt: String,
+    hash_password: String,
+    two_f_a: bool,
+    yubikey: Vec<u8>,
+}
+
+/// Users file at schema version 4, with a single YubiKey.
+#[derive(Serialize)]
+struct DocumentV4 {
+    version: u32,
+    data: HashMap<Email, UserV4>,
+}
+
+#[derive(Serialize)]
+struct UserV4 {
+    email: Email,
+    salt: String,
+    verifier: Vec<u8>,
+    two_f_a: bool,
+    yubikey: Vec<u8>,
+    totp: Option<()>,
+    recovery_codes: Vec<Vec<u8>>,
+    verified: bool,
+}
+
+/// Document at version 0 holding a user with the YubiKey of `token`.
+fn v0(token: &mut SoftwareToken) -> String {
+    let salt = generate_salt();
+    let user = UserV0 {
+        email: EMAIL.parse().unwrap(),
+        salt: salt.clone(),
+        hash_password: hash_password(PASSWORD, &salt).unwrap(),
+        two_f_a: true,
+        yubikey: token.generate().unwrap(),
+    };
+    ron::to_string(&DocumentV0 {
+        data: HashMap::from([(user.email.clone(), user)]),
+    })
+    .unwrap()
+}
+
+/// Document at version 4 holding a user with the YubiKey of `token`.
+fn v4(token: &mut SoftwareToken) -> String {
+    let salt = generate_salt();
+    let user = UserV4 {
+        email: EMAIL.parse().unwrap(),
+        verifier: srp_verifier(PASSWORD, &salt).unwrap(),
+        salt,
+        two_f_a: true,
+        yubikey: token.generate().unwrap(),
+        totp: None,
+        recovery_codes: vec![],
+        verified: true,
+    };
+    ron::to_string(&DocumentV4 {
+        version: 4,
+        data: HashMap::from([(user.email.clone(), user)]),
+    })
+    .unwrap()
+}
+
+/// Server started on `document`, with the user stored in it.
+fn upgraded(document: fn(&mut SoftwareToken) -> String) -> (TestServer, TestUser) {
+    let mut token = SoftwareToken::new(None, PIN);
+    let document = document(&mut token);
+    let server = TestServer::start_with(Transport::Noise, |_, dir| {
+        fs::write(dir.join("db.ron"), document).unwrap();
+    });
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.token = token;
+    (server, alice)
+}
+
+#[test]
+fn version_0_user_logs_in_after_the_upgrade() {
+    let (server, mut alice) = upgraded(v0);
+    alice.authenticate(&mut server.connect()).unwrap();
+    assert!(server.dir().join("db.ron.v0.bak").exists());
+}
+
+#[test]
+fn version_4_user_logs_in_after_the_upgrade() {
+    let (server, mut alice) = upgraded(v4);
+    alice.authenticate(&mut server.connect()).unwrap();
+    assert!(server.dir().join("db.ron.v4.bak").exists());
+}
+
+/// Exit code of the server binary run with `flag` on the storage of `dir`.
+fn run(dir: &Path, flag: &str) -> i32 {
+    let config = dir.join("server.ron");
+    fs::write(
+        &config,
+        format!("(storage: Ron(path: {:?}))", dir.join("db.ron")),
+    )
+    .unwrap();
+    Command::new(env!("CARGO_BIN_EXE_server"))
+        .arg(flag)
+        .arg(&config)
+        .status()
+        .unwrap()
+        .code()
+        .unwrap()
+}
+
+#[test]
+fn check_reports_the_migration_done_by_migrate() {
+    let dir = tempfile::tempdir().unwrap();
+    let document = v4(&mut SoftwareToken::new(None, PIN));
+    fs::write(dir.path().join("db.ron"), &document).unwrap();
+
+    assert_eq!(run(dir.path(), "--check"), 1);
+    assert_eq!(
+        fs::read_to_string(dir.path().join("db.ron")).unwrap(),
+        document
+    );
+    assert_eq!(run(dir.path(), "--migrate"), 0);
+    assert_eq!(run(dir.path(), "--check"), 0);
+}
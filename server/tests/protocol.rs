@@ -0,0 +1,82 @@
+mod common;
+
+use common::{TestServer, Transport};
+use server::config::Timeouts;
+use std::io::{self, Cursor, Read, Write};
+use std::net::TcpStream;
+use utils::frame::{read_frame, write_frame, MAX_FRAME_SIZE, PROTOCOL_VERSION};
+use utils::noise::NoiseStream;
+use utils::HelloData;
+
+/// Whether the server closed the connection, rather than still waiting for
+/// more bytes when the read timed out.
+fn closed(stream: &mut NoiseStream<TcpStream>) -> bool {
+    match stream.read(&mut [0; 1]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => !matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+    }
+}
+
+/// Server whose handshake timeout is far longer than `READ_TIMEOUT`, so that a
+/// closed connection was closed on purpose.
+fn patient_server() -> TestServer {
+    TestServer::start_with(Transport::Noise, |config, _| {
+        config.timeouts = Timeouts {
+            handshake: 60,
+            ..Timeouts::default()
+        };
+    })
+}
+
+#[test]
+fn oversized_frame_is_rejected_from_its_length() {
+    // Only the length is there: reading the payload would fail differently.
+    let mut length = Cursor::new((MAX_FRAME_SIZE + 1).to_be_bytes());
+    let error = read_frame::<_, HelloData>(&mut length).unwrap_err();
+    assert!(error.to_string().contains("exceeds"), "{}", error);
+
+    let server = patient_server();
+    let mut stream = server.connect_noise();
+    stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+    stream.flush().unwrap();
+    assert!(closed(&mut stream));
+}
+
+#[test]
+fn other_protocol_version_is_refused() {
+    let server = patient_server();
+    let mut stream = server.connect_noise();
+    write_frame(
+        &mut stream,
+        &HelloData {
+            version: PROTOCOL_VERSION + 1,
+        },
+    )
+    .unwrap();
+
+    let hello: HelloData = read_frame(&mut stream).unwrap();
+    assert_eq!(hello.version, PROTOCOL_VERSION);
+    assert!(closed(&mut stream));
+}
+
+#[test]
+fn empty_noise_messages_are_skipped() {
+    let server = patient_server();
+    let mut stream = server.connect_noise();
+    // Sent as a Noise message without payload.
+    assert_eq!(stream.write(&[]).unwrap(), 0);
+    write_frame(
+        &mut stream,
+        &HelloData {
+            version: PROTOCOL_VERSION,
+        },
+    )
+    .unwrap();
+
+    let hello: HelloData = read_frame(&mut stream).unwrap();
+    assert_eq!(hello.version, PROTOCOL_VERSION);
+}
@@ -0,0 +1,122 @@
+mod common;
+
+use common::{TestServer, Transport, READ_TIMEOUT};
+use server::config::{Config, Timeouts};
+use std::error::Error;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use utils::{Error as UtilsError, Request, Response};
+
+const EMAIL: &str = "alice@example.com";
+const PASSWORD: &str = "Password1!";
+
+fn with_timeouts(timeouts: Timeouts) -> TestServer {
+    TestServer::start_with(Transport::Noise, |config, _| config.timeouts = timeouts)
+}
+
+/// Whether the server closed `stream`, rather than still waiting for more
+/// bytes when the read timed out.
+fn closed(mut stream: impl Read) -> bool {
+    match stream.read(&mut [0; 1]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => !matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+    }
+}
+
+/// Whether `result` failed because the server closed the connection, rather
+/// than answering with an error.
+fn dropped<T>(result: Result<T, Box<dyn Error>>) -> bool {
+    matches!(result, Err(e) if e.downcast_ref::<UtilsError>().is_none())
+}
+
+#[test]
+fn silent_client_is_dropped_after_the_handshake_timeout() {
+    let server = with_timeouts(Timeouts {
+        handshake: 1,
+        ..Timeouts::default()
+    });
+    let stream = TcpStream::connect(server.address()).unwrap();
+    stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    assert!(closed(stream));
+}
+
+#[test]
+fn idle_session_is_dropped_after_the_idle_timeout() {
+    let server = with_timeouts(Timeouts {
+        idle: 1,
+        ..Timeouts::default()
+    });
+    let mut connection = server.connect();
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+    assert!(dropped(connection.request(&Request::ListKeys)));
+}
+
+#[test]
+fn sessions_beyond_the_maximum_wait_for_a_free_one() {
+    let server = TestServer::start_with(Transport::Noise, |config, _| config.max_sessions = 1);
+    let mut first = server.connect();
+
+    let (connected, waiting) = mpsc::channel();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut second = server.connect();
+            connected.send(()).unwrap();
+            second.send(&Request::Exit).unwrap();
+        });
+        assert!(waiting.recv_timeout(Duration::from_secs(1)).is_err());
+
+        first.send(&Request::Exit).unwrap();
+        waiting.recv_timeout(READ_TIMEOUT).unwrap();
+    });
+}
+
+#[test]
+fn no_sessions_is_refused() {
+    let path = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(path.path(), "(max_sessions: 0)").unwrap();
+    let error = Config::load(path.path()).unwrap_err();
+    assert!(error.to_string().contains("max_sessions"), "{}", error);
+}
+
+#[test]
+fn shutdown_lets_the_sessions_in_progress_finish() {
+    let mut server = TestServer::start(Transport::Noise);
+    let mut connection = server.connect();
+
+    server.shut_down();
+    assert!(!server.stopped(Duration::from_secs(1)));
+    let mut alice = server.user(EMAIL, PASSWORD);
+    alice.register(&mut connection).unwrap();
+    alice.authenticate(&mut connection).unwrap();
+    assert!(matches!(
+        connection.request(&Request::ListKeys).unwrap(),
+        Response::Keys(_)
+    ));
+
+    connection.send(&Request::Exit).unwrap();
+    assert!(server.stopped(READ_TIMEOUT));
+}
+
+#[test]
+fn shutdown_drops_the_sessions_past_its_timeout() {
+    let mut server = with_timeouts(Timeouts {
+        shutdown: 1,
+        ..Timeouts::default()
+    });
+    let mut connection = server.connect();
+
+    server.shut_down();
+    assert!(server.stopped(READ_TIMEOUT));
+    assert!(dropped(connection.request(&Request::ListKeys)));
+}
@@ -4,9 +4,27 @@ use argon2::{
 };
 use hmac::{Hmac, Mac};
 use rand::RngCore;
+use sha1::Sha1;
 use sha2::{Digest, Sha256};
+use srp::{
+    client::{SrpClient, SrpClientVerifier},
+    groups::G_2048,
+    server::SrpServer,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 type HmacSha256 = Hmac<Sha256>;
+type HmacSha1 = Hmac<Sha1>;
+
+/// Seconds during which a TOTP code is valid.
+pub const TOTP_PERIOD: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+
+/// SRP identity used in every verifier.
+///
+/// The email is not part of the verifier so that it stays valid if the user
+/// record is ever re-keyed; the per-user salt already makes verifiers unique.
+const SRP_IDENTITY: &[u8] = b"";
 
 pub fn hash_password(s: &str, salt: &str) -> Result<String, argon2::password_hash::Error> {
     let salt = SaltString::new(salt)?;
@@ -19,6 +37,17 @@ pub fn generate_salt() -> String {
     SaltString::generate(&mut OsRng).to_string()
 }
 
+/// Salt formatted like `generate_salt`, derived from `input` under `key` so
+/// that it is the same every time.
+pub fn derive_salt(key: &[u8], input: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(input);
+    let digest = mac.finalize().into_bytes();
+    SaltString::b64_encode(&digest[..16])
+        .expect("16 bytes make a valid salt")
+        .to_string()
+}
+
 pub fn generate_random_128_bits() -> [u8; 16] {
     let mut rng = rand::thread_rng();
     let mut dest: [u8; 16] = [0; 16];
@@ -26,6 +55,13 @@ pub fn generate_random_128_bits() -> [u8; 16] {
     dest
 }
 
+pub fn generate_random_256_bits() -> [u8; 32] {
+    let mut rng = rand::thread_rng();
+    let mut dest: [u8; 32] = [0; 32];
+    rng.fill_bytes(&mut dest);
+    dest
+}
+
 pub fn hmac_sha256(input: &[u8], key: &str) -> Result<Vec<u8>, String> {
     let mut mac = match HmacSha256::new_from_slice(key.as_bytes()) {
         Ok(mac) => mac,
@@ -40,3 +76,120 @@ pub fn hash_sha256(input: &[u8]) -> Vec<u8> {
     hasher.update(input);
     hasher.finalize()[..].to_vec()
 }
+
+/// Computes the SRP-6a verifier `v = g^x` stored by the server.
+///
+/// The password is hardened with Argon2 before entering SRP, so the verifier
+/// can neither be replayed as a credential nor cheaply brute forced.
+pub fn srp_verifier(password: &str, salt: &str) -> Result<Vec<u8>, String> {
+    let hash = hash_password(password, salt).map_err(|e| e.to_string())?;
+    Ok(srp_verifier_from_hash(&hash, salt))
+}
+
+/// Computes the SRP-6a verifier from a password already hashed with
+/// `hash_password`, as stored by the accounts created before SRP.
+pub fn srp_verifier_from_hash(hash: &str, salt: &str) -> Vec<u8> {
+    SrpClient::<Sha256>::new(&G_2048).compute_verifier(
+        SRP_IDENTITY,
+        hash.as_bytes(),
+        salt.as_bytes(),
+    )
+}
+
+/// Client public ephemeral `A = g^a`.
+pub fn srp_client_public(a: &[u8]) -> Vec<u8> {
+    SrpClient::<Sha256>::new(&G_2048).compute_public_ephemeral(a)
+}
+
+/// Server public ephemeral `B = kv + g^b`.
+pub fn srp_server_public(b: &[u8], verifier: &[u8]) -> Vec<u8> {
+    SrpServer::<Sha256>::new(&G_2048).compute_public_ephemeral(b, verifier)
+}
+
+/// Processes the server challenge on the client side.
+///
+/// The returned verifier holds the client proof to send and checks the
+/// server proof received in return.
+pub fn srp_client_proof(
+    a: &[u8],
+    password: &str,
+    salt: &str,
+    b_pub: &[u8],
+) -> Result<SrpClientVerifier<Sha256>, String> {
+    let hash = hash_password(password, salt).map_err(|e| e.to_string())?;
+    SrpClient::<Sha256>::new(&G_2048)
+        .process_reply(a, SRP_IDENTITY, hash.as_bytes(), salt.as_bytes(), b_pub)
+        .map_err(|e| e.to_string())
+}
+
+/// Checks the client proof on the server side and returns the server proof.
+pub fn srp_server_proof(
+    b: &[u8],
+    verifier: &[u8],
+    a_pub: &[u8],
+    client_proof: &[u8],
+) -> Result<Vec<u8>, String> {
+    let server = SrpServer::<Sha256>::new(&G_2048)
+        .process_reply(b, verifier, a_pub)
+        .map_err(|e| e.to_string())?;
+    server
+        .verify_client(client_proof)
+        .map_err(|e| e.to_string())?;
+    Ok(server.proof().to_vec())
+}
+
+/// Random 80 bits recovery code, as 4 groups of 4 base32 characters.
+pub fn generate_recovery_code() -> String {
+    let mut bytes = [0; 10];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let code = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes).to_lowercase();
+    code.as_bytes()
+        .chunks(4)
+        .map(|group| String::from_utf8_lossy(group))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Random 160 bits secret shared with an authenticator app.
+pub fn generate_totp_secret() -> Vec<u8> {
+    let mut secret = vec![0; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// TOTP time step of `time`, as defined by RFC 6238.
+pub fn totp_step(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() / TOTP_PERIOD)
+}
+
+/// Code of the authenticator app for the time step `step`, an HOTP value
+/// (RFC 4226) over HMAC-SHA1.
+pub fn totp_code(secret: &[u8], step: u64) -> String {
+    let mut mac = HmacSha1::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(TOTP_DIGITS),
+        width = TOTP_DIGITS as usize
+    )
+}
+
+/// `otpauth://` URI enrolling the secret in an authenticator app.
+pub fn totp_uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={}&issuer={issuer}&algorithm=SHA1&digits={}&period={}",
+        base32::encode(base32::Alphabet::RFC4648 { padding: false }, secret),
+        TOTP_DIGITS,
+        TOTP_PERIOD,
+    )
+}
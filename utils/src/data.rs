@@ -1,18 +1,70 @@
+use crate::{KeyCredential, SecondFactor};
 use serde::{Deserialize, Serialize};
-use validation::{Email, Password};
+use validation::{Email, KeyLabel, Otp, RecoveryCode, Token};
+
+// Connection
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HelloData {
+    pub version: u32,
+}
 
 // Register
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RegisterData {
     pub email: Email,
-    pub password: Password,
+    pub salt: String,
+    pub verifier: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VerifyData {
+    pub token: Token,
 }
 
+// Reset password
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PasswordData {
+    pub salt: String,
+    pub verifier: Vec<u8>,
+}
+
+// Second factor
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct YubiKeyData {
     pub yubikey: Vec<u8>,
 }
 
+/// Public key of a new YubiKey, under the label chosen for it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyData {
+    pub label: KeyLabel,
+    pub public_key: Vec<u8>,
+}
+
+/// Secret of a new authenticator app, with a code it generated to show it
+/// was enrolled.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TotpEnrollData {
+    pub secret: Vec<u8>,
+    pub code: Otp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TotpData {
+    pub code: Otp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecoveryCodeData {
+    pub code: RecoveryCode,
+}
+
+/// New recovery codes, only ever shown once.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecoveryCodesData {
+    pub codes: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct EmailData {
     pub email: Email,
@@ -20,34 +72,82 @@ pub struct EmailData {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ChallengeData {
-    pub salt: String,
     pub challenge: [u8; 16],
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct HmacData {
-    pub hmac: Vec<u8>,
+pub struct TokenData {
+    pub email: Email,
+    pub token: Token,
 }
 
+// Authenticate
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct ClientMessage {
-    pub message: String,
+pub struct SrpStartData {
+    pub email: Email,
+    pub a_pub: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SrpChallengeData {
+    pub salt: String,
+    pub b_pub: Vec<u8>,
+    pub challenge: [u8; 16],
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct ServerMessage {
-    pub message: String,
-    pub success: bool,
+pub struct SrpProofData {
+    pub proof: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct ServerMessage2FA {
-    pub message: String,
-    pub success: bool,
+pub struct PasswordVerifiedData {
+    pub proof: Vec<u8>,
     pub two_f_a: bool,
+    /// Second factors the user can choose from.
+    pub second_factors: Vec<SecondFactor>,
+}
+
+// Actions
+/// Step-up challenge, answered with any of the second factors of the user.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StepUpData {
+    pub challenge: [u8; 16],
+    pub second_factors: Vec<SecondFactor>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Switch2FA {
     pub two_f_a: bool,
 }
+
+/// Client public value starting the SRP exchange that proves the current
+/// password, the email being the one logged in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SrpPublicData {
+    pub a_pub: Vec<u8>,
+}
+
+/// Proof of the current password, with the salt and verifier of the new one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChangePasswordData {
+    pub proof: Vec<u8>,
+    pub salt: String,
+    pub verifier: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeysData {
+    pub keys: Vec<KeyCredential>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyLabelData {
+    pub label: KeyLabel,
+}
+
+/// JSON document of the data stored about the user.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExportData {
+    pub document: String,
+}
@@ -8,7 +8,23 @@ pub enum Error {
     InvalidEmail,
     UserAlreadyExist,
     TwoFAFailed,
+    TotpFailed,
+    RecoveryCodeFailed,
+    InvalidKey,
+    UnknownKey,
+    LastKey,
     UuidFailed,
+    EmailNotVerified,
+    /// Sensitive action asked without a recent YubiKey signature.
+    StepUpRequired,
+    /// Account changed or removed since the login, which has to be redone.
+    SessionExpired,
+    /// Too many failures, seconds to wait before trying again.
+    Throttled(u64),
+    IncompatibleVersion(u32),
+    UnexpectedRequest,
+    UnexpectedResponse,
+    ServerError,
 }
 
 impl fmt::Display for Error {
@@ -18,7 +34,27 @@ impl fmt::Display for Error {
             Self::InvalidEmail => write!(f, "Invalid email"),
             Self::UserAlreadyExist => write!(f, "User already exists"),
             Self::TwoFAFailed => write!(f, "2FA Failed"),
+            Self::TotpFailed => write!(f, "Wrong or already used authenticator code"),
+            Self::RecoveryCodeFailed => write!(f, "Wrong or already used recovery code"),
+            Self::InvalidKey => write!(
+                f,
+                "Invalid YubiKey public key, or label already used by another key"
+            ),
+            Self::UnknownKey => write!(f, "No YubiKey with this label"),
+            Self::LastKey => write!(f, "The last YubiKey cannot be revoked"),
             Self::UuidFailed => write!(f, "Wrong UUID"),
+            Self::EmailNotVerified => write!(f, "Email not verified yet"),
+            Self::StepUpRequired => write!(f, "Confirm your identity with a second factor first"),
+            Self::SessionExpired => write!(f, "Session expired, please log in again"),
+            Self::Throttled(seconds) => {
+                write!(f, "Too many failed attempts, retry in {} seconds", seconds)
+            }
+            Self::IncompatibleVersion(version) => {
+                write!(f, "Incompatible protocol version {}", version)
+            }
+            Self::UnexpectedRequest => write!(f, "Request not allowed at this point"),
+            Self::UnexpectedResponse => write!(f, "Unexpected response from the server"),
+            Self::ServerError => write!(f, "Internal server error"),
         }
     }
 }
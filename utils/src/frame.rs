@@ -0,0 +1,55 @@
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error::Error;
+use std::io::{Read, Write};
+
+/// Version of the wire protocol, exchanged in the hello of every connection.
+pub const PROTOCOL_VERSION: u32 = 12;
+
+/// Largest frame a peer may send, so it can't make us allocate arbitrary sizes.
+pub const MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_FRAME_SIZE as u64)
+        .reject_trailing_bytes()
+}
+
+/// Writes `o` as a single frame: a four-byte big-endian length, then the
+/// bincode payload.
+pub fn write_frame<W, T>(stream: &mut W, o: &T) -> Result<(), Box<dyn Error>>
+where
+    W: Write + ?Sized,
+    T: Serialize,
+{
+    let payload = options().serialize(o)?;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    stream.write_all(&frame)?;
+    Ok(stream.flush()?)
+}
+
+/// Reads a single frame, rejecting it before allocation if it is too large.
+pub fn read_frame<R, T>(stream: &mut R) -> Result<T, Box<dyn Error>>
+where
+    R: Read + ?Sized,
+    T: DeserializeOwned,
+{
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_SIZE {
+        return Err(format!(
+            "Frame of {} bytes exceeds the {} bytes limit",
+            len, MAX_FRAME_SIZE
+        )
+        .into());
+    }
+
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(options().deserialize(&payload)?)
+}
@@ -1,13 +1,19 @@
 pub mod crypto;
 mod data;
 mod errors;
+pub mod frame;
+pub mod noise;
+mod protocol;
 mod strings;
 mod user;
 
 pub use data::{
-    ChallengeData, ClientMessage, EmailData, HmacData, RegisterData, ServerMessage,
-    ServerMessage2FA, Switch2FA, YubiKeyData,
+    ChallengeData, ChangePasswordData, EmailData, ExportData, HelloData, KeyData, KeyLabelData,
+    KeysData, PasswordData, PasswordVerifiedData, RecoveryCodeData, RecoveryCodesData,
+    RegisterData, SrpChallengeData, SrpProofData, SrpPublicData, SrpStartData, StepUpData,
+    Switch2FA, TokenData, TotpData, TotpEnrollData, VerifyData, YubiKeyData,
 };
 pub use errors::Error;
+pub use protocol::{Request, Response};
 pub use strings::Strings;
-pub use user::User;
+pub use user::{KeyAlgorithm, KeyCredential, SecondFactor, TotpCredential, User};
@@ -0,0 +1,146 @@
+use snow::{Builder, HandshakeState, TransportState};
+use std::error::Error;
+use std::io::{self, Read, Write};
+
+/// Noise pattern used by both sides. XX gives forward secrecy and transmits the
+/// server static key, which the client checks against its pinned copy.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+
+/// Generates a static Noise keypair, returned as `(private, public)`.
+pub fn generate_keypair() -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let keypair = Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+    Ok((keypair.private, keypair.public))
+}
+
+/// Encrypted stream over an established Noise session.
+///
+/// Every write is split into Noise transport messages, each sent with a two
+/// byte big-endian length prefix.
+pub struct NoiseStream<S: Read + Write> {
+    stream: S,
+    transport: TransportState,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<S: Read + Write> NoiseStream<S> {
+    /// Runs the handshake as the responder with the server static key.
+    pub fn accept(mut stream: S, private_key: &[u8]) -> Result<NoiseStream<S>, Box<dyn Error>> {
+        let mut handshake = Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(private_key)
+            .build_responder()?;
+
+        read_handshake(&mut stream, &mut handshake)?;
+        write_handshake(&mut stream, &mut handshake)?;
+        read_handshake(&mut stream, &mut handshake)?;
+
+        NoiseStream::new(stream, handshake)
+    }
+
+    /// Runs the handshake as the initiator and aborts it if the server static
+    /// key is not the pinned one.
+    pub fn connect(
+        mut stream: S,
+        server_public_key: &[u8],
+    ) -> Result<NoiseStream<S>, Box<dyn Error>> {
+        let (private_key, _) = generate_keypair()?;
+        let mut handshake = Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&private_key)
+            .build_initiator()?;
+
+        write_handshake(&mut stream, &mut handshake)?;
+        read_handshake(&mut stream, &mut handshake)?;
+        if handshake.get_remote_static() != Some(server_public_key) {
+            return Err("Server static key does not match the pinned key".into());
+        }
+        write_handshake(&mut stream, &mut handshake)?;
+
+        NoiseStream::new(stream, handshake)
+    }
+
+    fn new(stream: S, handshake: HandshakeState) -> Result<NoiseStream<S>, Box<dyn Error>> {
+        Ok(NoiseStream {
+            stream,
+            transport: handshake.into_transport_mode()?,
+            buffer: vec![],
+            position: 0,
+        })
+    }
+}
+
+impl<S: Read + Write> Read for NoiseStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Skips empty transport messages, returning no bytes would mean the
+        // end of the stream.
+        while self.position == self.buffer.len() {
+            let message = read_message(&mut self.stream)?;
+            let mut payload = vec![0; message.len()];
+            let len = self
+                .transport
+                .read_message(&message, &mut payload)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            payload.truncate(len);
+            self.buffer = payload;
+            self.position = 0;
+        }
+
+        let len = buf.len().min(self.buffer.len() - self.position);
+        buf[..len].copy_from_slice(&self.buffer[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl<S: Read + Write> Write for NoiseStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_MESSAGE_LEN - TAG_LEN);
+        let mut message = vec![0; len + TAG_LEN];
+        let message_len = self
+            .transport
+            .write_message(&buf[..len], &mut message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_message(&mut self.stream, &message[..message_len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn read_handshake<S: Read>(
+    stream: &mut S,
+    handshake: &mut HandshakeState,
+) -> Result<(), Box<dyn Error>> {
+    let message = read_message(stream)?;
+    handshake.read_message(&message, &mut vec![0; MAX_MESSAGE_LEN])?;
+    Ok(())
+}
+
+fn write_handshake<S: Write>(
+    stream: &mut S,
+    handshake: &mut HandshakeState,
+) -> Result<(), Box<dyn Error>> {
+    let mut message = vec![0; MAX_MESSAGE_LEN];
+    let len = handshake.write_message(&[], &mut message)?;
+    write_message(stream, &message[..len])?;
+    Ok(stream.flush()?)
+}
+
+fn read_message<S: Read>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut message = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+fn write_message<S: Write>(stream: &mut S, message: &[u8]) -> io::Result<()> {
+    stream.write_all(&(message.len() as u16).to_be_bytes())?;
+    stream.write_all(message)
+}
@@ -0,0 +1,65 @@
+use crate::{
+    ChallengeData, ChangePasswordData, EmailData, Error, ExportData, KeyData, KeyLabelData,
+    KeysData, PasswordData, PasswordVerifiedData, RecoveryCodeData, RecoveryCodesData,
+    RegisterData, SrpChallengeData, SrpProofData, SrpPublicData, SrpStartData, StepUpData, Strings,
+    Switch2FA, TokenData, TotpData, TotpEnrollData, VerifyData, YubiKeyData,
+};
+use serde::{Deserialize, Serialize};
+
+/// Every message the client can send. The server answers each one with
+/// exactly one `Response`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Request {
+    // Register
+    Register(RegisterData),
+    RegisterYubiKey(KeyData),
+    RegisterTotp(TotpEnrollData),
+    VerifyEmail(VerifyData),
+    ResendVerification,
+    // Authenticate
+    StartAuthentication(SrpStartData),
+    ProvePassword(SrpProofData),
+    ProveYubiKey(YubiKeyData),
+    ProveTotp(TotpData),
+    ProveRecoveryCode(RecoveryCodeData),
+    // Reset password
+    StartReset(EmailData),
+    RedeemToken(TokenData),
+    SetPassword(PasswordData),
+    // Revert an email change, then set a new password
+    RevertEmail(TokenData),
+    // Actions
+    StartStepUp,
+    ProveStepUp(YubiKeyData),
+    ProveStepUpTotp(TotpData),
+    ProveStepUpRecoveryCode(RecoveryCodeData),
+    Switch2FA,
+    EnrollTotp(TotpEnrollData),
+    RegenerateRecoveryCodes,
+    ListKeys,
+    EnrollKey(KeyData),
+    RevokeKey(KeyLabelData),
+    StartPasswordChange(SrpPublicData),
+    ChangePassword(ChangePasswordData),
+    StartEmailChange(EmailData),
+    ConfirmEmailChange(VerifyData),
+    ExportData,
+    DeleteAccount,
+    Logout,
+    Exit,
+}
+
+/// Every message the server can send.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Response {
+    Success(Strings),
+    SrpChallenge(SrpChallengeData),
+    PasswordVerified(PasswordVerifiedData),
+    Challenge(ChallengeData),
+    StepUp(StepUpData),
+    RecoveryCodes(RecoveryCodesData),
+    Keys(KeysData),
+    Export(ExportData),
+    Switched2FA(Switch2FA),
+    Error(Error),
+}
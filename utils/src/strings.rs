@@ -4,11 +4,22 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Strings {
+    AccountDeleted,
     AuthSuccess,
     AuthTo2FA,
+    EmailChanged,
     EmailMessage,
+    EmailReverted,
     EmailSent,
     EmailSubject,
+    EmailVerified,
+    KeyEnrolled,
+    KeyRevoked,
+    LoggedOut,
+    PasswordChanged,
+    PasswordReset,
+    StepUpSuccess,
+    TotpEnrolled,
     UserRegistered,
     UuidSuccess,
     YubiKeyPubInfo,
@@ -17,12 +28,27 @@ pub enum Strings {
 impl fmt::Display for Strings {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::AccountDeleted => write!(f, "Account deleted"),
             Self::AuthSuccess => write!(f, "Authentication success"),
             Self::AuthTo2FA => write!(f, "Proceeding with the 2FA"),
+            Self::EmailChanged => write!(f, "Email changed"),
             Self::EmailMessage => write!(f, "You can reset your password with the provided token"),
+            Self::EmailReverted => {
+                write!(f, "Email change reverted, log in or reset your password")
+            }
             Self::EmailSent => write!(f, "An email was sent to your address"),
             Self::EmailSubject => write!(f, "Reset your password"),
-            Self::UserRegistered => write!(f, "User registered"),
+            Self::EmailVerified => write!(f, "Email verified, you can now log in"),
+            Self::KeyEnrolled => write!(f, "YubiKey enrolled"),
+            Self::KeyRevoked => write!(f, "YubiKey revoked"),
+            Self::LoggedOut => write!(f, "Logged out"),
+            Self::PasswordChanged => write!(f, "Password changed"),
+            Self::PasswordReset => write!(f, "Password reset"),
+            Self::StepUpSuccess => write!(f, "Identity confirmed"),
+            Self::TotpEnrolled => write!(f, "Authenticator app enrolled"),
+            Self::UserRegistered => {
+                write!(f, "Registration received, check your emails to confirm it")
+            }
             Self::UuidSuccess => write!(f, "Correct UUID"),
             Self::YubiKeyPubInfo => write!(f, "Proceeding with the YubiKey"),
         }
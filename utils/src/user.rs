@@ -1,14 +1,74 @@
-use crate::crypto::generate_salt;
+use crate::crypto::{generate_random_256_bits, generate_salt};
 use serde::{Deserialize, Serialize};
-use validation::Email;
+use std::fmt;
+use validation::{Email, KeyLabel};
+
+/// Ways of proving the second factor.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecondFactor {
+    YubiKey,
+    Totp,
+    RecoveryCode,
+}
+
+impl fmt::Display for SecondFactor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::YubiKey => write!(f, "YubiKey"),
+            Self::Totp => write!(f, "Authenticator app (TOTP)"),
+            Self::RecoveryCode => write!(f, "Recovery codes"),
+        }
+    }
+}
+
+/// Signature algorithms of the YubiKeys.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    /// ECDSA over P-256 with SHA-256, the public key SEC1 encoded.
+    EcdsaP256,
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EcdsaP256 => write!(f, "ECDSA P-256"),
+        }
+    }
+}
+
+/// YubiKey enrolled by a user. Times are in seconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyCredential {
+    pub label: KeyLabel,
+    pub public_key: Vec<u8>,
+    pub algorithm: KeyAlgorithm,
+    pub created: u64,
+    /// Last successful login or reset with the key, if any.
+    pub last_used: Option<u64>,
+}
+
+/// Secret shared with an authenticator app.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TotpCredential {
+    pub secret: Vec<u8>,
+    /// Last time step accepted, the codes up to it cannot be used again.
+    pub last_step: u64,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct User {
     pub email: Email,
     pub salt: String,
-    pub hash_password: String,
+    pub verifier: Vec<u8>,
     pub two_f_a: bool,
-    pub yubikey: Vec<u8>,
+    /// YubiKeys enrolled, any of which can prove the second factor.
+    pub yubikeys: Vec<KeyCredential>,
+    pub totp: Option<TotpCredential>,
+    /// SHA-256 hashes of the recovery codes not used yet.
+    pub recovery_codes: Vec<Vec<u8>>,
+    /// Whether the owner of the email confirmed it, the account cannot be
+    /// used before.
+    pub verified: bool,
 }
 
 impl Default for User {
@@ -16,9 +76,29 @@ impl Default for User {
         User {
             email: Email::default(),
             salt: generate_salt(),
-            hash_password: "hash_password".to_string(),
+            verifier: generate_random_256_bits().to_vec(),
             two_f_a: true,
-            yubikey: vec![],
+            yubikeys: vec![],
+            totp: None,
+            recovery_codes: vec![],
+            verified: false,
+        }
+    }
+}
+
+impl User {
+    /// Second factors enrolled, any of which can be used to log in.
+    pub fn second_factors(&self) -> Vec<SecondFactor> {
+        let mut factors = vec![];
+        if !self.yubikeys.is_empty() {
+            factors.push(SecondFactor::YubiKey);
+        }
+        if self.totp.is_some() {
+            factors.push(SecondFactor::Totp);
+        }
+        if !self.recovery_codes.is_empty() {
+            factors.push(SecondFactor::RecoveryCode);
         }
+        factors
     }
 }
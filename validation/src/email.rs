@@ -31,9 +31,9 @@ impl FromStr for Email {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if EMAIL_RULE.is_match(s) {
-            return Ok(Email(String::from(s)));
+            Ok(Email(String::from(s)))
         } else {
-            return Err(EmailError);
+            Err(EmailError)
         }
     }
 }
@@ -0,0 +1,37 @@
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref KEY_LABEL_RULE: Regex = Regex::new(r"^[[:alnum:]][[:alnum:] _.-]{0,31}$").unwrap();
+}
+
+/// Name given to a YubiKey, telling the keys of a user apart.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, Hash, PartialEq)]
+#[repr(transparent)]
+pub struct KeyLabel(String);
+
+impl std::ops::Deref for KeyLabel {
+    type Target = String;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+pub struct KeyLabelError;
+
+impl FromStr for KeyLabel {
+    type Err = KeyLabelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if KEY_LABEL_RULE.is_match(s) {
+            Ok(KeyLabel(String::from(s)))
+        } else {
+            Err(KeyLabelError)
+        }
+    }
+}
@@ -1,9 +1,15 @@
 mod email;
+mod key_label;
+mod otp;
 mod password;
 mod pin;
+mod recovery_code;
 mod token;
 
 pub use email::Email;
+pub use key_label::KeyLabel;
+pub use otp::Otp;
 pub use password::Password;
 pub use pin::Pin;
+pub use recovery_code::RecoveryCode;
 pub use token::Token;
@@ -0,0 +1,37 @@
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref OTP_RULE: Regex = Regex::new(r"^[[:digit:]]{6}$").unwrap();
+}
+
+/// One-time code of an authenticator app.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, Hash, PartialEq)]
+#[repr(transparent)]
+pub struct Otp(String);
+
+impl std::ops::Deref for Otp {
+    type Target = String;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+pub struct OtpError;
+
+impl FromStr for Otp {
+    type Err = OtpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if OTP_RULE.is_match(s) {
+            Ok(Otp(String::from(s)))
+        } else {
+            Err(OtpError)
+        }
+    }
+}
@@ -36,9 +36,9 @@ impl FromStr for Password {
             && PW_DIGIT_RULE.is_match(s)
             && PW_SPECIAL_RULE.is_match(s)
         {
-            return Ok(Password(String::from(s)));
+            Ok(Password(String::from(s)))
         } else {
-            return Err(PasswordError);
+            Err(PasswordError)
         }
     }
 }
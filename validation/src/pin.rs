@@ -28,9 +28,9 @@ impl FromStr for Pin {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if PIN_RULE.is_match(s) {
-            return Ok(Pin(String::from(s)));
+            Ok(Pin(String::from(s)))
         } else {
-            return Err(PinError);
+            Err(PinError)
         }
     }
 }
@@ -0,0 +1,38 @@
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref RECOVERY_CODE_RULE: Regex = Regex::new(r"^[a-z2-7]{4}(-[a-z2-7]{4}){3}$").unwrap();
+}
+
+/// Single-use code replacing the second factor, case insensitive.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, Hash, PartialEq)]
+#[repr(transparent)]
+pub struct RecoveryCode(String);
+
+impl std::ops::Deref for RecoveryCode {
+    type Target = String;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+pub struct RecoveryCodeError;
+
+impl FromStr for RecoveryCode {
+    type Err = RecoveryCodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        if RECOVERY_CODE_RULE.is_match(&s) {
+            Ok(RecoveryCode(s))
+        } else {
+            Err(RecoveryCodeError)
+        }
+    }
+}
@@ -29,9 +29,9 @@ impl FromStr for Token {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if UUID_RULE.is_match(s) {
-            return Ok(Token(String::from(s)));
+            Ok(Token(String::from(s)))
         } else {
-            return Err(TokenError);
+            Err(TokenError)
         }
     }
 }